@@ -1,16 +1,17 @@
 use env_logger::TimestampPrecision;
 use eyre::Result;
 use log::{error, info};
-use tiny_maps::Map;
+use std::sync::Arc;
+use tiny_maps::{HttpTileSource, Map, MbTilesSource, PmTilesSource, TileSource};
 use tokio::sync::mpsc;
 use winit::{
     dpi::PhysicalSize,
-    event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
+    event::{ElementState, Event, KeyboardInput, MouseScrollDelta, VirtualKeyCode, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
 
-const HELSINKI: (f32, f32) = (24.945831, 60.192_06);
+const HELSINKI: (f64, f64) = (24.945831, 60.192_06);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -24,7 +25,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .build(&event_loop)?;
     let (tx, mut rx) = mpsc::channel(32);
 
-    let mut map = Map::new(&HELSINKI.into(), 15, window).await?;
+    let source: Arc<dyn TileSource> = match std::env::args().nth(1) {
+        Some(path) if path.ends_with(".pmtiles") => Arc::new(PmTilesSource::open(path).await?),
+        Some(path) => Arc::new(MbTilesSource::open(path)?),
+        None => Arc::new(HttpTileSource::openstreetmap()?.with_disk_cache(
+            std::env::temp_dir().join("tiny_maps_osm"),
+            256 * 1024 * 1024,
+        )?),
+    };
+    let mut map = Map::new(&HELSINKI.into(), 15.0, window, source).await?;
 
     tokio::spawn(async move {
         while let Some(event) = rx.recv().await {
@@ -50,17 +59,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         use VirtualKeyCode::*;
                         match key {
                             U => {
-                                match tokio::try_join!(map.set_zoom(map.zoom() + 1)) {
+                                match tokio::try_join!(map.set_zoom(map.zoom() + 1.0)) {
                                     Ok(_) => {}
                                     Err(e) => error!("Failed to zoom in {}", e),
                                 };
                             }
                             Y => {
-                                let current_zoom = map.zoom();
-                                if current_zoom == 0 {
-                                    return;
-                                }
-                                match tokio::try_join!(map.set_zoom(current_zoom - 1)) {
+                                match tokio::try_join!(map.set_zoom(map.zoom() - 1.0)) {
                                     Ok(_) => {}
                                     Err(e) => error!("Failed to zoom out {}", e),
                                 };
@@ -100,6 +105,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             _ => {}
                         }
                     }
+                    WindowEvent::MouseWheel { delta, .. } => {
+                        let delta = match delta {
+                            MouseScrollDelta::LineDelta(_, y) => y as f64 / 4.0,
+                            MouseScrollDelta::PixelDelta(position) => position.y / 200.0,
+                        };
+                        match tokio::try_join!(map.set_zoom(map.zoom() + delta)) {
+                            Ok(_) => {}
+                            Err(e) => error!("Failed to zoom {}", e),
+                        };
+                    }
                     WindowEvent::Resized(new_size) => {
                         match tokio::try_join!(map.update_window_size(&new_size)) {
                             Ok(_) => {}
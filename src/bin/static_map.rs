@@ -0,0 +1,218 @@
+use eyre::{eyre, Result};
+use geo::Point;
+use std::sync::Arc;
+use tiny_maps::{
+    Backend, HttpTileSource, MbTilesSource, OffscreenMap, Overlay, PmTilesSource, TileSource,
+};
+
+const USAGE: &str = "Usage: static_map --center LNG,LAT --zoom Z [options]
+
+Options:
+    --size WxH              image size in pixels, 800x600 by default
+    --source SOURCE         .mbtiles or .pmtiles file or a tile URL template with
+                            {z}, {x} and {y}, OpenStreetMap by default
+    --marker LNG,LAT        draws a marker, can be repeated
+    --marker-color RRGGBB
+    --path LNG,LAT;LNG,LAT  draws a line through the points, can be repeated
+    --path-color RRGGBB
+    --path-width PIXELS
+    --output FILE           map.png by default, the format follows the extension
+    --backend BACKEND       gpu, software or auto, auto falls back to software
+                            when there is no GPU";
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    center: Point<f64>,
+    zoom: f64,
+    width: u32,
+    height: u32,
+    source: Option<String>,
+    markers: Vec<Point<f64>>,
+    marker_color: Option<[u8; 4]>,
+    paths: Vec<Vec<Point<f64>>>,
+    path_color: Option<[u8; 4]>,
+    path_width: Option<f32>,
+    output: String,
+    backend: Backend,
+}
+
+impl Args {
+    fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self> {
+        let mut center = None;
+        let mut zoom = None;
+        let mut parsed = Args {
+            center: Point::new(0.0, 0.0),
+            zoom: 0.0,
+            width: 800,
+            height: 600,
+            source: None,
+            markers: Vec::new(),
+            marker_color: None,
+            paths: Vec::new(),
+            path_color: None,
+            path_width: None,
+            output: "map.png".to_owned(),
+            backend: Backend::default(),
+        };
+
+        let mut args = args.into_iter();
+        while let Some(name) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| eyre!("Missing value for {}", name))?;
+            match name.as_str() {
+                "--center" => center = Some(parse_point(&value)?),
+                "--zoom" => zoom = Some(value.parse()?),
+                "--size" => {
+                    let (width, height) = split(&value, 'x')?;
+                    parsed.width = width.parse()?;
+                    parsed.height = height.parse()?;
+                }
+                "--source" => parsed.source = Some(value),
+                "--marker" => parsed.markers.push(parse_point(&value)?),
+                "--marker-color" => parsed.marker_color = Some(parse_color(&value)?),
+                "--path" => parsed
+                    .paths
+                    .push(value.split(';').map(parse_point).collect::<Result<_>>()?),
+                "--path-color" => parsed.path_color = Some(parse_color(&value)?),
+                "--path-width" => parsed.path_width = Some(value.parse()?),
+                "--output" => parsed.output = value,
+                "--backend" => parsed.backend = parse_backend(&value)?,
+                _ => return Err(eyre!("Unknown option {}", name)),
+            }
+        }
+
+        parsed.center = center.ok_or_else(|| eyre!("--center is required"))?;
+        parsed.zoom = zoom.ok_or_else(|| eyre!("--zoom is required"))?;
+        Ok(parsed)
+    }
+
+    fn overlays(&self) -> Vec<Overlay> {
+        let mut overlays = Vec::new();
+        for points in &self.paths {
+            let mut path = Overlay::path(points.clone());
+            if let Some(color) = self.path_color {
+                path = path.with_color(color);
+            }
+            if let Some(width) = self.path_width {
+                path = path.with_size(width);
+            }
+            overlays.push(path);
+        }
+        for point in &self.markers {
+            let mut marker = Overlay::marker(*point);
+            if let Some(color) = self.marker_color {
+                marker = marker.with_color(color);
+            }
+            overlays.push(marker);
+        }
+        overlays
+    }
+
+    async fn source(&self) -> Result<Arc<dyn TileSource>> {
+        let source: Arc<dyn TileSource> = match &self.source {
+            Some(path) if path.ends_with(".pmtiles") => Arc::new(PmTilesSource::open(path).await?),
+            Some(path) if path.ends_with(".mbtiles") => Arc::new(MbTilesSource::open(path)?),
+            Some(url_template) => Arc::new(HttpTileSource::new(url_template)?),
+            None => Arc::new(HttpTileSource::openstreetmap()?),
+        };
+        Ok(source)
+    }
+}
+
+fn split(value: &str, separator: char) -> Result<(&str, &str)> {
+    let mut parts = value.splitn(2, separator);
+    match (parts.next(), parts.next()) {
+        (Some(first), Some(second)) => Ok((first.trim(), second.trim())),
+        _ => Err(eyre!(
+            "Expected two values separated by '{}' in {}",
+            separator,
+            value
+        )),
+    }
+}
+
+fn parse_point(value: &str) -> Result<Point<f64>> {
+    let (lng, lat) = split(value, ',')?;
+    Ok(Point::new(lng.parse()?, lat.parse()?))
+}
+
+fn parse_backend(value: &str) -> Result<Backend> {
+    match value {
+        "auto" => Ok(Backend::Auto),
+        "gpu" => Ok(Backend::Gpu),
+        "software" => Ok(Backend::Software),
+        _ => Err(eyre!("Unknown backend {}", value)),
+    }
+}
+
+fn parse_color(value: &str) -> Result<[u8; 4]> {
+    let value = value.trim_start_matches('#');
+    if value.len() != 6 {
+        return Err(eyre!("Expected a RRGGBB color, got {}", value));
+    }
+    let channel = |i: usize| u8::from_str_radix(&value[i..i + 2], 16);
+    Ok([channel(0)?, channel(2)?, channel(4)?, 255])
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let mut map =
+        OffscreenMap::with_backend(args.width, args.height, args.source().await?, args.backend)
+            .await?;
+    for overlay in args.overlays() {
+        map.add_overlay(overlay);
+    }
+    map.render_to_file(&args.center, args.zoom, &args.output)
+        .await?;
+
+    if let Some(attribution) = map.attribution() {
+        eprintln!("{}", attribution);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<Args> {
+        Args::parse(line.split(' ').map(str::to_owned))
+    }
+
+    #[test]
+    fn it_works() {
+        let parsed = args(
+            "--center 24.94,60.19 --zoom 12.5 --size 400x300 --marker 24.9,60.1 \
+             --marker 25,60.2 --path 24.9,60.1;25,60.2 --path-color 00ff80 --output out.png --backend software",
+        )
+        .unwrap();
+        assert_eq!(parsed.center, Point::new(24.94, 60.19));
+        assert_eq!(parsed.zoom, 12.5);
+        assert_eq!((parsed.width, parsed.height), (400, 300));
+        assert_eq!(parsed.markers.len(), 2);
+        assert_eq!(
+            parsed.paths,
+            vec![vec![Point::new(24.9, 60.1), Point::new(25.0, 60.2)]]
+        );
+        assert_eq!(parsed.path_color, Some([0, 255, 128, 255]));
+        assert_eq!(parsed.output, "out.png");
+        assert_eq!(parsed.backend, Backend::Software);
+        assert_eq!(parsed.overlays().len(), 3);
+
+        assert!(args("--zoom 3").is_err());
+        assert!(args("--center 1,2 --zoom").is_err());
+        assert!(args("--center 1,2 --zoom 3 --marker-color red").is_err());
+        assert!(args("--center 1,2 --zoom 3 --backend vulkan").is_err());
+    }
+}
@@ -0,0 +1,507 @@
+use crate::tile_id::TileId;
+use bytes::Bytes;
+use eyre::{eyre, Result};
+use hyper::{
+    header::{CACHE_CONTROL, ETAG, EXPIRES, LAST_MODIFIED},
+    HeaderMap,
+};
+use log::{debug, warn};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const TILE_EXTENSION: &str = "tile";
+const META_EXTENSION: &str = "meta";
+const TEMP_EXTENSION: &str = "tmp";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct CachePolicy {
+    pub expires: Option<SystemTime>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl CachePolicy {
+    /// Returns `None` when the response must not be stored at all.
+    pub fn from_headers(headers: &HeaderMap, now: SystemTime) -> Option<Self> {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+
+        let mut max_age = None;
+        let mut no_cache = false;
+        if let Some(cache_control) = header(CACHE_CONTROL) {
+            for directive in cache_control.split(',').map(|d| d.trim().to_lowercase()) {
+                if directive == "no-store" {
+                    return None;
+                } else if directive == "no-cache" {
+                    no_cache = true;
+                } else if let Some(value) = directive.strip_prefix("max-age=") {
+                    max_age = value.trim_matches('"').parse::<u64>().ok();
+                }
+            }
+        }
+
+        let etag = header(ETAG).map(str::to_owned);
+        let last_modified = header(LAST_MODIFIED).map(str::to_owned);
+
+        let expires = if no_cache {
+            None
+        } else if let Some(max_age) = max_age {
+            Some(now + Duration::from_secs(max_age))
+        } else if let Some(expires) = header(EXPIRES) {
+            // An invalid Expires value means already expired
+            httpdate::parse_http_date(expires).ok()
+        } else {
+            // Heuristic freshness of 10% of the document age, as suggested by RFC 7234
+            last_modified
+                .as_deref()
+                .and_then(|lm| httpdate::parse_http_date(lm).ok())
+                .and_then(|lm| now.duration_since(lm).ok())
+                .map(|age| now + age / 10)
+        };
+
+        Some(Self {
+            expires,
+            etag,
+            last_modified,
+        })
+    }
+
+    pub fn is_fresh(&self, now: SystemTime) -> bool {
+        matches!(self.expires, Some(expires) if expires > now)
+    }
+
+    /// Keeps validators from the stored response when a 304 does not repeat them.
+    pub fn merge(mut self, stored: &CachePolicy) -> Self {
+        if self.etag.is_none() {
+            self.etag = stored.etag.clone();
+        }
+        if self.last_modified.is_none() {
+            self.last_modified = stored.last_modified.clone();
+        }
+        self
+    }
+
+    fn serialize(&self) -> String {
+        let mut out = String::new();
+        if let Some(expires) = self.expires.and_then(|e| e.duration_since(UNIX_EPOCH).ok()) {
+            out.push_str(&format!("expires {}\n", expires.as_secs()));
+        }
+        if let Some(etag) = &self.etag {
+            out.push_str(&format!("etag {}\n", etag));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            out.push_str(&format!("last-modified {}\n", last_modified));
+        }
+        out
+    }
+
+    fn parse(text: &str) -> Self {
+        let mut policy = CachePolicy::default();
+        for line in text.lines() {
+            let mut parts = line.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some("expires"), Some(value)) => {
+                    policy.expires = value
+                        .parse()
+                        .ok()
+                        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+                }
+                (Some("etag"), Some(value)) => policy.etag = Some(value.to_owned()),
+                (Some("last-modified"), Some(value)) => {
+                    policy.last_modified = Some(value.to_owned())
+                }
+                _ => {}
+            }
+        }
+        policy
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CachedTile {
+    pub data: Bytes,
+    pub policy: CachePolicy,
+}
+
+#[derive(Debug)]
+struct IndexEntry {
+    size: u64,
+    meta_size: u64,
+    last_access: u64,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    entries: HashMap<PathBuf, IndexEntry>,
+    total_size: u64,
+    clock: u64,
+}
+
+impl Index {
+    fn touch(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct DiskCache {
+    root: PathBuf,
+    max_size: u64,
+    index: Mutex<Index>,
+    temp_counter: AtomicU64,
+}
+
+impl DiskCache {
+    pub fn open<P: AsRef<Path>>(root: P, max_size: u64) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+
+        let mut files = Vec::new();
+        Self::scan(&root, &mut files)?;
+        // Restore the access order from modification times
+        files.sort();
+
+        let mut index = Index::default();
+        for (_, path, size) in files {
+            let meta_size = fs::metadata(path.with_extension(META_EXTENSION))
+                .map(|metadata| metadata.len())
+                .unwrap_or(0);
+            let last_access = index.touch();
+            index.total_size += size + meta_size;
+            index.entries.insert(
+                path,
+                IndexEntry {
+                    size,
+                    meta_size,
+                    last_access,
+                },
+            );
+        }
+        debug!(
+            "Disk cache {:?} has {} tiles, {} bytes",
+            root,
+            index.entries.len(),
+            index.total_size
+        );
+
+        let cache = Self {
+            root,
+            max_size,
+            index: Mutex::new(index),
+            temp_counter: AtomicU64::new(0),
+        };
+        for path in cache.take_evicted()? {
+            let _ = fs::remove_file(&path);
+            let _ = fs::remove_file(path.with_extension(META_EXTENSION));
+        }
+        Ok(cache)
+    }
+
+    fn scan(dir: &Path, files: &mut Vec<(SystemTime, PathBuf, u64)>) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                Self::scan(&path, files)?;
+            } else if path.extension() == Some(OsStr::new(TILE_EXTENSION)) {
+                files.push((metadata.modified()?, path, metadata.len()));
+            } else if path.extension() == Some(OsStr::new(TEMP_EXTENSION)) {
+                // Left over from a write that was interrupted
+                debug!("Removing {:?} from disk cache", path);
+                let _ = fs::remove_file(&path);
+            }
+        }
+        Ok(())
+    }
+
+    fn tile_path(&self, id: &TileId) -> PathBuf {
+        self.root
+            .join(id.z().to_string())
+            .join(id.x().to_string())
+            .join(format!("{}.{}", id.y(), TILE_EXTENSION))
+    }
+
+    pub async fn load(&self, id: &TileId) -> Result<Option<CachedTile>> {
+        let path = self.tile_path(id);
+        {
+            let mut index = self.lock_index()?;
+            let last_access = index.touch();
+            match index.entries.get_mut(&path) {
+                Some(entry) => entry.last_access = last_access,
+                None => return Ok(None),
+            }
+        }
+
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => Bytes::from(data),
+            Err(e) => {
+                warn!("Failed to read cached tile {:?}: {}", path, e);
+                self.forget(&path)?;
+                return Ok(None);
+            }
+        };
+        let policy = match tokio::fs::read_to_string(path.with_extension(META_EXTENSION)).await {
+            Ok(text) => CachePolicy::parse(&text),
+            Err(_) => CachePolicy::default(),
+        };
+
+        Ok(Some(CachedTile { data, policy }))
+    }
+
+    pub async fn store(&self, id: &TileId, data: &[u8], policy: &CachePolicy) -> Result<()> {
+        let path = self.tile_path(id);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Write next to the target and rename so readers never see a partial tile. The name is
+        // unique so concurrent writers of the same tile, also from other processes, don't clash.
+        let tmp = path.with_extension(format!(
+            "{}.{}.{}",
+            std::process::id(),
+            self.temp_counter.fetch_add(1, Ordering::Relaxed),
+            TEMP_EXTENSION
+        ));
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        let meta_size = Self::write_policy(&path, policy).await?;
+
+        {
+            let mut index = self.lock_index()?;
+            let size = data.len() as u64;
+            let last_access = index.touch();
+            let previous = index.entries.insert(
+                path,
+                IndexEntry {
+                    size,
+                    meta_size,
+                    last_access,
+                },
+            );
+            index.total_size += size + meta_size;
+            if let Some(previous) = previous {
+                index.total_size -= previous.size + previous.meta_size;
+            }
+        }
+
+        self.evict().await
+    }
+
+    /// Does nothing when the tile is not cached, e.g. after it was evicted.
+    pub async fn store_policy(&self, id: &TileId, policy: &CachePolicy) -> Result<()> {
+        let path = self.tile_path(id);
+        if !self.lock_index()?.entries.contains_key(&path) {
+            return Ok(());
+        }
+        let meta_size = Self::write_policy(&path, policy).await?;
+        let evicted = {
+            let mut index = self.lock_index()?;
+            match index.entries.get_mut(&path) {
+                Some(entry) => {
+                    let previous = std::mem::replace(&mut entry.meta_size, meta_size);
+                    index.total_size = index.total_size + meta_size - previous;
+                    false
+                }
+                None => true,
+            }
+        };
+        if evicted {
+            // The tile went away while the policy was written
+            let _ = tokio::fs::remove_file(path.with_extension(META_EXTENSION)).await;
+            return Ok(());
+        }
+        self.evict().await
+    }
+
+    /// Writes the policy next to the tile at `path` and returns its size in bytes.
+    async fn write_policy(path: &Path, policy: &CachePolicy) -> Result<u64> {
+        let text = policy.serialize();
+        tokio::fs::write(path.with_extension(META_EXTENSION), &text).await?;
+        Ok(text.len() as u64)
+    }
+
+    #[cfg(test)]
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.lock_index()?.total_size)
+    }
+
+    async fn evict(&self) -> Result<()> {
+        // Files are removed after the index lock is released
+        for path in self.take_evicted()? {
+            debug!("Evicting {:?} from disk cache", path);
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                warn!("Failed to evict {:?}: {}", path, e);
+            }
+            let _ = tokio::fs::remove_file(path.with_extension(META_EXTENSION)).await;
+        }
+        Ok(())
+    }
+
+    /// Drops the least recently used tiles from the index until it fits in `max_size` and
+    /// returns their paths.
+    fn take_evicted(&self) -> Result<Vec<PathBuf>> {
+        let mut index = self.lock_index()?;
+        let mut evicted = Vec::new();
+        if index.total_size <= self.max_size {
+            return Ok(evicted);
+        }
+
+        let mut by_age: Vec<_> = index
+            .entries
+            .iter()
+            .map(|(path, entry)| (entry.last_access, path.clone()))
+            .collect();
+        by_age.sort();
+
+        for (_, path) in by_age {
+            if index.total_size <= self.max_size {
+                break;
+            }
+            if let Some(entry) = index.entries.remove(&path) {
+                index.total_size -= entry.size + entry.meta_size;
+            }
+            evicted.push(path);
+        }
+
+        Ok(evicted)
+    }
+
+    fn forget(&self, path: &Path) -> Result<()> {
+        let mut index = self.lock_index()?;
+        if let Some(entry) = index.entries.remove(path) {
+            index.total_size -= entry.size + entry.meta_size;
+        }
+        Ok(())
+    }
+
+    fn lock_index(&self) -> Result<std::sync::MutexGuard<'_, Index>> {
+        self.index
+            .lock()
+            .map_err(|_| eyre!("Disk cache index is poisoned"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "tiny_maps_disk_cache_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn cache_policy() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=600"),
+        );
+        headers.insert(ETAG, HeaderValue::from_static("\"abc\""));
+        headers.insert(
+            EXPIRES,
+            HeaderValue::from_static("Thu, 01 Jan 1970 00:00:00 GMT"),
+        );
+        let policy = CachePolicy::from_headers(&headers, now).unwrap();
+        assert_eq!(policy.expires, Some(now + Duration::from_secs(600)));
+        assert_eq!(policy.etag.as_deref(), Some("\"abc\""));
+        assert!(policy.is_fresh(now));
+        assert!(!policy.is_fresh(now + Duration::from_secs(601)));
+        assert_eq!(CachePolicy::parse(&policy.serialize()), policy);
+
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        let policy = CachePolicy::from_headers(&headers, now).unwrap();
+        assert!(!policy.is_fresh(now));
+
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        assert_eq!(CachePolicy::from_headers(&headers, now), None);
+
+        headers.remove(CACHE_CONTROL);
+        let policy = CachePolicy::from_headers(&headers, now).unwrap();
+        assert_eq!(policy.expires, Some(UNIX_EPOCH));
+        assert!(!policy.is_fresh(now));
+    }
+
+    #[tokio::test]
+    async fn it_works() {
+        let dir = temp_dir("it_works");
+        let policy = CachePolicy {
+            etag: Some("\"v1\"".to_owned()),
+            ..Default::default()
+        };
+        // Every tile also has a policy file
+        let meta = policy.serialize().len() as u64;
+
+        let cache = DiskCache::open(&dir, 10 + 2 * meta).unwrap();
+        assert!(cache.load(&TileId::new(1, 2, 3)).await.unwrap().is_none());
+        cache
+            .store(&TileId::new(1, 2, 3), b"12345", &policy)
+            .await
+            .unwrap();
+        cache
+            .store(&TileId::new(2, 2, 3), b"6789", &policy)
+            .await
+            .unwrap();
+        let cached = cache.load(&TileId::new(1, 2, 3)).await.unwrap().unwrap();
+        assert_eq!(&cached.data[..], b"12345");
+        assert_eq!(cached.policy, policy);
+        assert_eq!(cache.size().unwrap(), 9 + 2 * meta);
+
+        // Tile 2/2/3 is the least recently used one and goes first
+        cache
+            .store(&TileId::new(3, 2, 3), b"abc", &policy)
+            .await
+            .unwrap();
+        assert!(cache.load(&TileId::new(2, 2, 3)).await.unwrap().is_none());
+        assert_eq!(cache.size().unwrap(), 8 + 2 * meta);
+
+        cache
+            .store_policy(&TileId::new(3, 2, 3), &CachePolicy::default())
+            .await
+            .unwrap();
+        assert_eq!(cache.size().unwrap(), 8 + meta);
+
+        // The index is rebuilt from disk on restart
+        let cache = DiskCache::open(&dir, 10 + meta).unwrap();
+        assert_eq!(cache.size().unwrap(), 8 + meta);
+        assert!(cache.load(&TileId::new(3, 2, 3)).await.unwrap().is_some());
+
+        // Shrinking the cache evicts on open
+        let cache = DiskCache::open(&dir, 3).unwrap();
+        assert_eq!(cache.size().unwrap(), 3);
+        assert!(!dir.join("3/1/2.meta").exists());
+
+        // Policies of tiles that are not cached are not written
+        cache
+            .store_policy(&TileId::new(1, 2, 3), &policy)
+            .await
+            .unwrap();
+        assert!(!dir.join("3/1/2.meta").exists());
+        assert_eq!(cache.size().unwrap(), 3);
+
+        // Interrupted writes are cleaned up on open
+        fs::write(dir.join("3/3/2.1234.0.tmp"), b"partial").unwrap();
+        let cache = DiskCache::open(&dir, 3).unwrap();
+        assert_eq!(cache.size().unwrap(), 3);
+        assert!(!dir.join("3/3/2.1234.0.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+use std::{error::Error, fmt, time::Duration};
+
+#[derive(Debug)]
+pub enum TileError {
+    NotFound,
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    ServerError {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+    UnexpectedStatus(u16),
+    InvalidImage {
+        content_type: Option<String>,
+    },
+    Timeout,
+    Network(Box<dyn Error + Send + Sync>),
+    /// The data looked like an image but could not be decoded.
+    Decode(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for TileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TileError::NotFound => write!(f, "Tile not found"),
+            TileError::RateLimited { .. } => write!(f, "Rate limited by the tile server"),
+            TileError::ServerError { status, .. } => write!(f, "Tile server error {}", status),
+            TileError::UnexpectedStatus(status) => write!(f, "Unexpected HTTP status {}", status),
+            TileError::InvalidImage {
+                content_type: Some(content_type),
+            } => write!(f, "Tile is not an image but {}", content_type),
+            TileError::InvalidImage { content_type: None } => write!(f, "Tile is not an image"),
+            TileError::Timeout => write!(f, "Tile request timed out"),
+            TileError::Network(e) => write!(f, "Network error: {}", e),
+            TileError::Decode(e) => write!(f, "Failed to decode tile: {}", e),
+        }
+    }
+}
+
+impl TileError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            TileError::RateLimited { .. }
+                | TileError::ServerError { .. }
+                | TileError::Timeout
+                | TileError::Network(_)
+        )
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            TileError::RateLimited { retry_after } | TileError::ServerError { retry_after, .. } => {
+                *retry_after
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Error for TileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TileError::Network(e) | TileError::Decode(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
@@ -1,12 +1,38 @@
 #[macro_use]
 extern crate derivative;
 
+mod disk_cache;
+mod error;
+#[cfg(feature = "gpu")]
 mod map;
 mod network_manager;
+mod offscreen;
+mod overlay;
+mod placeholder;
+pub mod projection;
 mod render;
+mod scheduler;
+mod source;
 mod tile;
+mod tile_cache;
 mod tile_coordinates;
 mod tile_id;
+mod tile_loader;
 mod utils;
+mod view;
 
+pub use error::TileError;
+#[cfg(feature = "gpu")]
 pub use map::Map;
+pub use network_manager::{HttpConfig, RetryPolicy};
+pub use offscreen::OffscreenMap;
+pub use overlay::Overlay;
+pub use placeholder::Placeholder;
+pub use render::Backend;
+pub use source::{
+    Compression, FileRangeReader, HttpTileSource, MbTilesMetadata, MbTilesSource, PmTilesHeader,
+    PmTilesSource, RangeReader, TileSource, TileType,
+};
+pub use tile_cache::CacheStats;
+pub use tile_id::TileId;
+pub use tile_loader::TileState;
@@ -1,210 +1,210 @@
-use super::network_manager::NetworkManager;
 use super::render::Painter;
-use crate::{tile::Tile, tile_coordinates::TileCoordinates, tile_id::TileId};
-use bytes::Bytes;
+use crate::{
+    placeholder::Placeholder,
+    projection,
+    source::TileSource,
+    tile_cache::CacheStats,
+    tile_id::TileId,
+    tile_loader::{TileLoader, TileState},
+    view::{self, TILE_SIZE},
+};
 use eyre::Result;
-use futures::future::try_join_all;
 use geo::Point;
 use log::{debug, info};
 use std::{collections::HashMap, sync::Arc, time::Instant};
-use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 use winit::{dpi::PhysicalSize, window::Window};
 
-const TILE_SIZE: f32 = 256.0;
-const PI: f32 = std::f64::consts::PI as f32;
-
 pub struct Map {
-    point: Point<f32>,
-    zoom: f32,
+    point: Point<f64>,
+    zoom: f64,
     painter: Painter,
-    nm: NetworkManager,
-    width: f32,
-    height: f32,
-    window: Window,
-    tile_cache: Arc<Mutex<HashMap<TileId, Arc<Bytes>>>>,
-}
-
-struct TileInfo {
-    pub id: TileId,
-    pub coords: TileCoordinates,
+    width: f64,
+    height: f64,
+    window: Arc<Window>,
+    loader: Arc<TileLoader>,
+    load_task: Option<JoinHandle<()>>,
 }
 
 impl Map {
-    pub async fn new(point: &Point<f32>, zoom: u32, window: Window) -> Result<Self> {
-        let nm = NetworkManager::new()?;
-        let scale_factor = window.scale_factor() as f32;
+    pub async fn new(
+        point: &Point<f64>,
+        zoom: f64,
+        window: Window,
+        source: Arc<dyn TileSource>,
+    ) -> Result<Self> {
+        let scale_factor = window.scale_factor();
         let PhysicalSize { width, height } = window.inner_size();
-        let width = width as f32 / scale_factor;
-        let height = height as f32 / scale_factor;
-
-        let zoom = zoom as f32;
-        let tile_cache = Arc::new(Mutex::new(HashMap::new()));
-        let tiles = Map::load_tiles(zoom, point, width, height, &nm, tile_cache.clone()).await?;
-        let painter = Painter::new(&window, &tiles).await?;
-
-        let map = Self {
-            point: *point,
+        let width = width as f64 / scale_factor;
+        let height = height as f64 / scale_factor;
+
+        let zoom = view::clamp_zoom(zoom, source.as_ref());
+        let window = Arc::new(window);
+        let loader = Arc::new(TileLoader::new(source, {
+            let window = window.clone();
+            move || window.request_redraw()
+        }));
+        let painter = Painter::new(&window, &Placeholder::default(), TILE_SIZE as u32).await?;
+
+        let mut map = Self {
+            point: Point::new(projection::wrap_longitude(point.lng()), point.lat()),
             zoom,
             painter,
-            nm,
             width,
             height,
             window,
-            tile_cache,
+            loader,
+            load_task: None,
         };
+        map.update().await?;
 
         info!("Map created");
         Ok(map)
     }
 
+    /// Draws immediately with the tiles available, tiles that arrived since the last frame
+    /// are picked up here.
     pub async fn render(&mut self) -> Result<()> {
         let now = Instant::now();
 
+        if self.loader.take_dirty() {
+            self.update_grid().await?;
+        }
+
         self.painter.render()?;
         debug!("Render took {} ms", now.elapsed().as_millis());
 
         Ok(())
     }
 
-    async fn load_tiles(
-        zoom: f32,
-        point: &Point<f32>,
-        width: f32,
-        height: f32,
-        nm: &NetworkManager,
-        cache: Arc<Mutex<HashMap<TileId, Arc<Bytes>>>>,
-    ) -> Result<Vec<Tile>> {
-        let now = Instant::now();
-        let required_tiles = Map::create_required_tile_infos(zoom, point, width, height);
-        let mut lock = cache.lock().await;
-        let to_download = { Map::not_available_tiles(&(*lock), &required_tiles) };
-        let mut futures = Vec::new();
-        for id in &to_download {
-            let load_tile_future = nm.load_tile(id);
-            futures.push(load_tile_future);
-        }
+    async fn update_grid(&mut self) -> Result<()> {
+        let camera = view::camera(self.zoom, &self.point, self.width, self.height);
+        let required_tiles = view::create_required_tile_infos(self.zoom, &camera);
+        let viewport = view::viewport(&camera);
+        let tiles = view::collect_tiles(&self.loader, required_tiles, &viewport).await;
+        self.painter.load_textures(&tiles)
+    }
 
-        let new_tiles = try_join_all(futures).await?;
-        for new_tile in new_tiles {
-            lock.insert(new_tile.0, new_tile.1);
-        }
-        debug!("Tile loading took {} ms", now.elapsed().as_millis());
-        let tiles: Result<Vec<_>, _> = required_tiles
-            .into_iter()
-            .map(|t| {
-                let data = lock.get(&t.id).unwrap().clone();
-                Ok(Tile::new(&t.id, data, &t.coords))
-            })
-            .collect();
+    pub fn attribution(&self) -> Option<&str> {
+        self.loader.source().attribution()
+    }
+
+    pub async fn cache_stats(&self) -> CacheStats {
+        self.loader.cache().lock().await.stats()
+    }
+
+    pub async fn set_cache_limits(&self, max_entries: usize, max_bytes: usize) {
+        self.loader
+            .cache()
+            .lock()
+            .await
+            .set_limits(max_entries, max_bytes);
+    }
+
+    /// Limits the tile textures kept on the GPU, so tiles panned back into view are not
+    /// uploaded again.
+    pub fn set_texture_cache_limit(&mut self, max_textures: usize) {
+        self.painter.set_max_textures(max_textures);
+    }
 
-        tiles
+    /// State of every tile in the current viewport.
+    pub async fn tile_states(&self) -> HashMap<TileId, TileState> {
+        self.loader.states().await
     }
 
-    pub fn zoom(&self) -> u32 {
-        self.zoom as u32
+    pub async fn set_placeholder(&mut self, placeholder: &Placeholder) -> Result<()> {
+        self.painter.set_placeholder(placeholder, TILE_SIZE as u32);
+        self.update_grid().await?;
+        self.window.request_redraw();
+        Ok(())
     }
 
-    pub async fn set_zoom(&mut self, zoom: u32) -> Result<()> {
-        self.zoom = zoom as f32;
+    pub fn zoom(&self) -> f64 {
+        self.zoom
+    }
+
+    /// Fractional levels draw the tiles of the nearest integer level scaled.
+    pub async fn set_zoom(&mut self, zoom: f64) -> Result<()> {
+        self.zoom = view::clamp_zoom(zoom, self.loader.source());
         self.update().await?;
         Ok(())
     }
 
-    pub fn point(&self) -> Point<f32> {
+    pub fn point(&self) -> Point<f64> {
         self.point
     }
 
-    pub async fn set_point(&mut self, point: Point<f32>) -> Result<()> {
-        self.point = point;
+    /// Longitudes outside of -180..180 are wrapped around the world.
+    pub async fn set_point(&mut self, point: Point<f64>) -> Result<()> {
+        self.point = Point::new(projection::wrap_longitude(point.lng()), point.lat());
         self.update().await?;
         Ok(())
     }
 
+    /// Geographic position under a screen position, in logical pixels from the top left
+    /// corner of the window.
+    pub fn screen_to_lnglat(&self, screen: &Point<f64>) -> Point<f64> {
+        let camera = view::camera(self.zoom, &self.point, self.width, self.height);
+        let pixel = camera.screen_to_world(screen);
+        let point = projection::world_pixel_to_lnglat(&pixel, self.zoom.round());
+        Point::new(projection::wrap_longitude(point.lng()), point.lat())
+    }
+
+    /// Screen position of `point` in logical pixels, outside the window when the point is
+    /// not visible. Of the repeated worlds the copy closest to the centre is used.
+    pub fn lnglat_to_screen(&self, point: &Point<f64>) -> Point<f64> {
+        let camera = view::camera(self.zoom, &self.point, self.width, self.height);
+        view::screen_pixel(point, &camera, self.zoom)
+    }
+
+    /// World pixel of `point` at the current, possibly fractional, zoom.
+    pub fn lnglat_to_world_pixel(&self, point: &Point<f64>) -> Point<f64> {
+        projection::lnglat_to_world_pixel(point, self.zoom)
+    }
+
+    /// Tile containing `point` at the zoom level whose tiles are drawn.
+    pub fn tile_for_lnglat(&self, point: &Point<f64>) -> TileId {
+        projection::tile_for_lnglat(point, self.zoom.round() as u8)
+    }
+
     pub async fn update_window_size(&mut self, size: &PhysicalSize<u32>) -> Result<()> {
-        let scale_factor = self.window.scale_factor() as f32;
-        self.width = size.width as f32 / scale_factor;
-        self.height = size.height as f32 / scale_factor;
+        let scale_factor = self.window.scale_factor();
+        self.width = size.width as f64 / scale_factor;
+        self.height = size.height as f64 / scale_factor;
         self.update().await?;
         Ok(())
     }
 
+    /// Redraws right away with the cached tiles and starts loading the missing ones in the
+    /// background, replacing the loading of the previous viewport. Tiles that stay visible
+    /// keep their textures, so mostly only the camera changes.
     async fn update(&mut self) -> Result<()> {
         let now = Instant::now();
-        let tiles = Map::load_tiles(
-            self.zoom,
-            &self.point,
-            self.width,
-            self.height,
-            &self.nm,
-            self.tile_cache.clone(),
-        )
-        .await?;
+        let camera = view::camera(self.zoom, &self.point, self.width, self.height);
+        let required_tiles = view::create_required_tile_infos(self.zoom, &camera);
+        let ids = view::ids_by_distance(&required_tiles);
+        self.loader.set_required(&ids).await;
 
+        let viewport = view::viewport(&camera);
+        let tiles = view::collect_tiles(&self.loader, required_tiles, &viewport).await;
         self.painter.load_textures(&tiles)?;
+        self.painter.set_camera(&camera);
+
+        if let Some(task) = self.load_task.take() {
+            task.abort();
+        }
+        self.load_task = Some(tokio::spawn(self.loader.clone().run(ids)));
 
         self.window.request_redraw();
         debug!("Update took {} ms", now.elapsed().as_millis());
         Ok(())
     }
+}
 
-    fn get_corner_info(
-        zoom: f32,
-        point: &Point<f32>,
-        width: f32,
-        height: f32,
-    ) -> (f32, f32, TileId) {
-        let tile_across = 2f32.powf(zoom);
-        let world_size = TILE_SIZE * tile_across;
-        let mercator_x = world_size * (point.lng() / 360.0 + 0.5);
-        let mercator_y =
-            world_size * (1.0 - ((PI * (0.25 + point.lat() / 360.0)).tan().ln()) / PI) / 2.0;
-        let x0 = (mercator_x - width / 2.0).floor();
-        let y0 = (mercator_y - height / 2.0).floor();
-        let tile_x = (x0 / TILE_SIZE).floor();
-        let tile_y = (y0 / TILE_SIZE).floor();
-        (x0, y0, TileId::new(tile_x, tile_y, zoom))
-    }
-
-    fn create_required_tile_infos(
-        zoom: f32,
-        point: &Point<f32>,
-        width: f32,
-        height: f32,
-    ) -> Vec<TileInfo> {
-        let (x0, y0, corner_tile_id) = Map::get_corner_info(zoom, point, width, height);
-        let mut tiles = Vec::new();
-
-        let mut tile_x = corner_tile_id.x;
-        let mut tile_y = corner_tile_id.y;
-
-        while (tile_y * TILE_SIZE) < (y0 + height) {
-            while (tile_x * TILE_SIZE) < (x0 + width) {
-                let left = tile_x * TILE_SIZE - x0;
-                let top = tile_y * TILE_SIZE - y0;
-                let coords = TileCoordinates::new(left, top, width, height, TILE_SIZE);
-                let id = TileId::new(tile_x, tile_y, zoom);
-                tiles.push(TileInfo { id, coords });
-                tile_x += 1.0;
-            }
-            tile_y += 1.0;
-            tile_x = corner_tile_id.x;
-        }
-
-        tiles
-    }
-
-    fn not_available_tiles(
-        cache: &HashMap<TileId, Arc<Bytes>>,
-        required: &[TileInfo],
-    ) -> Vec<TileId> {
-        let mut out = Vec::new();
-
-        for tile in required {
-            if !cache.contains_key(&tile.id) {
-                out.push(tile.id.clone());
-            }
+impl Drop for Map {
+    fn drop(&mut self) {
+        if let Some(task) = self.load_task.take() {
+            task.abort();
         }
-
-        out
     }
 }
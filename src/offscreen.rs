@@ -0,0 +1,161 @@
+use crate::{
+    overlay::Overlay,
+    placeholder::Placeholder,
+    render::{Backend, Renderer},
+    source::TileSource,
+    tile_loader::TileLoader,
+    view::{self, TILE_SIZE},
+};
+use eyre::Result;
+use geo::Point;
+use image::RgbaImage;
+use log::debug;
+use std::{path::Path, sync::Arc, time::Instant};
+
+/// Renders maps into images without a window, e.g. in backend services or in CI.
+pub struct OffscreenMap {
+    renderer: Box<dyn Renderer>,
+    loader: Arc<TileLoader>,
+    overlays: Vec<Overlay>,
+    width: u32,
+    height: u32,
+}
+
+impl OffscreenMap {
+    /// Uses the default backend, see `Backend::default`.
+    pub async fn new(width: u32, height: u32, source: Arc<dyn TileSource>) -> Result<Self> {
+        OffscreenMap::with_backend(width, height, source, Backend::default()).await
+    }
+
+    pub async fn with_backend(
+        width: u32,
+        height: u32,
+        source: Arc<dyn TileSource>,
+        backend: Backend,
+    ) -> Result<Self> {
+        let renderer = backend
+            .create(width, height, &Placeholder::default(), TILE_SIZE as u32)
+            .await?;
+        let loader = Arc::new(TileLoader::new(source, || {}));
+        Ok(Self {
+            renderer,
+            loader,
+            overlays: Vec::new(),
+            width,
+            height,
+        })
+    }
+
+    pub fn attribution(&self) -> Option<&str> {
+        self.loader.source().attribution()
+    }
+
+    pub fn set_placeholder(&mut self, placeholder: &Placeholder) {
+        self.renderer.set_placeholder(placeholder, TILE_SIZE as u32);
+    }
+
+    /// Overlays are drawn over the tiles in the order they were added.
+    pub fn add_overlay(&mut self, overlay: Overlay) {
+        self.overlays.push(overlay);
+    }
+
+    pub fn clear_overlays(&mut self) {
+        self.overlays.clear();
+    }
+
+    /// Loads the tiles around `point` and draws them with the overlays, tiles that failed
+    /// to load are drawn with the placeholder.
+    pub async fn render(&mut self, point: &Point<f64>, zoom: f64) -> Result<RgbaImage> {
+        let now = Instant::now();
+        let zoom = view::clamp_zoom(zoom, self.loader.source());
+        let camera = view::camera(zoom, point, self.width as f64, self.height as f64);
+        let required_tiles = view::create_required_tile_infos(zoom, &camera);
+        let ids = view::ids_by_distance(&required_tiles);
+        self.loader.set_required(&ids).await;
+        self.loader.load(&ids).await;
+
+        let viewport = view::viewport(&camera);
+        let tiles = view::collect_tiles(&self.loader, required_tiles, &viewport).await;
+        self.renderer.load_textures(&tiles)?;
+        self.renderer.set_camera(&camera);
+        let mut image = self.renderer.render_to_image().await?;
+        for overlay in &self.overlays {
+            overlay.draw(&mut image, |p| {
+                let screen = view::screen_pixel(p, &camera, zoom);
+                (screen.x() as f32, screen.y() as f32)
+            });
+        }
+        debug!("Offscreen render took {} ms", now.elapsed().as_millis());
+        Ok(image)
+    }
+
+    /// Renders like `render` and saves the image, the format follows the file extension.
+    pub async fn render_to_file<P: AsRef<Path>>(
+        &mut self,
+        point: &Point<f64>,
+        zoom: f64,
+        path: P,
+    ) -> Result<()> {
+        self.render(point, zoom).await?.save(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::TileError, tile_id::TileId};
+    use bytes::Bytes;
+    use futures::future::{BoxFuture, FutureExt};
+    use image::Rgba;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+
+    /// Only the north west tile of zoom level 1 exists.
+    struct NorthWestSource;
+
+    impl TileSource for NorthWestSource {
+        fn load_tile<'a>(&'a self, id: &'a TileId) -> BoxFuture<'a, Result<Bytes>> {
+            let result = if *id == TileId::new(0, 0, 1) {
+                let mut data = Vec::new();
+                image::DynamicImage::ImageRgba8(RgbaImage::from_pixel(256, 256, Rgba(RED)))
+                    .write_to(&mut data, image::ImageOutputFormat::Png)
+                    .map(|_| Bytes::from(data))
+                    .map_err(Into::into)
+            } else {
+                Err(TileError::NotFound.into())
+            };
+            futures::future::ready(result).boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn it_works() {
+        let source = Arc::new(NorthWestSource);
+        let mut map = OffscreenMap::with_backend(512, 384, source, Backend::Software)
+            .await
+            .unwrap();
+        map.set_placeholder(&Placeholder::Solid(BLUE));
+        map.add_overlay(
+            Overlay::marker(Point::new(0.0, 0.0))
+                .with_color([0, 255, 0, 255])
+                .with_size(4.0),
+        );
+
+        // Maps can be moved into spawned tasks
+        fn assert_send<T: Send>(_: &T) {}
+        assert_send(&map);
+        let center = Point::new(0.0, 0.0);
+        let render = map.render(&center, 1.0);
+        assert_send(&render);
+        let image = render.await.unwrap();
+        assert_eq!(image.dimensions(), (512, 384));
+        // The world is 512 pixels wide, its middle is in the middle of the image
+        assert_eq!(image.get_pixel(10, 10).0, RED);
+        assert_eq!(image.get_pixel(250, 100).0, RED);
+        assert_eq!(image.get_pixel(262, 100).0, BLUE);
+        assert_eq!(image.get_pixel(10, 374).0, BLUE);
+        assert_eq!(image.get_pixel(256, 192).0, [0, 255, 0, 255]);
+    }
+}
@@ -0,0 +1,143 @@
+use geo::Point;
+use image::{Rgba, RgbaImage};
+
+/// Shapes drawn over the tiles, positions are longitude and latitude.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Overlay {
+    Marker {
+        point: Point<f64>,
+        color: [u8; 4],
+        radius: f32,
+    },
+    Path {
+        points: Vec<Point<f64>>,
+        color: [u8; 4],
+        width: f32,
+    },
+}
+
+impl Overlay {
+    pub fn marker(point: Point<f64>) -> Self {
+        Overlay::Marker {
+            point,
+            color: [220, 40, 40, 255],
+            radius: 6.0,
+        }
+    }
+
+    pub fn path(points: Vec<Point<f64>>) -> Self {
+        Overlay::Path {
+            points,
+            color: [40, 80, 220, 255],
+            width: 3.0,
+        }
+    }
+
+    pub fn with_color(mut self, new_color: [u8; 4]) -> Self {
+        match &mut self {
+            Overlay::Marker { color, .. } | Overlay::Path { color, .. } => *color = new_color,
+        }
+        self
+    }
+
+    /// Marker radius or path width in pixels.
+    pub fn with_size(mut self, size: f32) -> Self {
+        match &mut self {
+            Overlay::Marker { radius, .. } => *radius = size,
+            Overlay::Path { width, .. } => *width = size,
+        }
+        self
+    }
+
+    /// `project` maps a position to image pixels.
+    pub(crate) fn draw<F>(&self, image: &mut RgbaImage, project: F)
+    where
+        F: Fn(&Point<f64>) -> (f32, f32),
+    {
+        match self {
+            Overlay::Marker {
+                point,
+                color,
+                radius,
+            } => {
+                let center = project(point);
+                fill_segment(image, center, center, *radius, *color);
+            }
+            Overlay::Path {
+                points,
+                color,
+                width,
+            } => {
+                let points: Vec<_> = points.iter().map(project).collect();
+                for segment in points.windows(2) {
+                    fill_segment(image, segment[0], segment[1], width / 2.0, *color);
+                }
+            }
+        }
+    }
+}
+
+/// Blends every pixel whose centre is within `radius` of the segment from `a` to `b`.
+fn fill_segment(image: &mut RgbaImage, a: (f32, f32), b: (f32, f32), radius: f32, color: [u8; 4]) {
+    let (width, height) = image.dimensions();
+    let min_x = (a.0.min(b.0) - radius).floor().max(0.0) as u32;
+    let min_y = (a.1.min(b.1) - radius).floor().max(0.0) as u32;
+    let max_x = (a.0.max(b.0) + radius).ceil().min(width as f32) as u32;
+    let max_y = (a.1.max(b.1) + radius).ceil().min(height as f32) as u32;
+
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length = dx * dx + dy * dy;
+    for y in min_y..max_y {
+        for x in min_x..max_x {
+            let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+            let t = if length > 0.0 {
+                (((px - a.0) * dx + (py - a.1) * dy) / length).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let distance = (px - a.0 - t * dx).hypot(py - a.1 - t * dy);
+            if distance <= radius {
+                blend(image.get_pixel_mut(x, y), color);
+            }
+        }
+    }
+}
+
+fn blend(pixel: &mut Rgba<u8>, color: [u8; 4]) {
+    let alpha = color[3] as u32;
+    for (channel, value) in pixel.0.iter_mut().zip(&color).take(3) {
+        *channel = ((*value as u32 * alpha + *channel as u32 * (255 - alpha)) / 255) as u8;
+    }
+    pixel.0[3] = pixel.0[3].max(color[3]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+
+    fn project(p: &Point<f64>) -> (f32, f32) {
+        (p.x() as f32, p.y() as f32)
+    }
+
+    #[test]
+    fn it_works() {
+        let mut image = RgbaImage::from_pixel(20, 20, Rgba(WHITE));
+        Overlay::marker(Point::new(10.0, 10.0))
+            .with_color([0, 0, 0, 255])
+            .with_size(3.0)
+            .draw(&mut image, project);
+        assert_eq!(image.get_pixel(10, 10).0, [0, 0, 0, 255]);
+        assert_eq!(image.get_pixel(12, 10).0, [0, 0, 0, 255]);
+        assert_eq!(image.get_pixel(14, 10).0, WHITE);
+
+        let mut image = RgbaImage::from_pixel(20, 20, Rgba(WHITE));
+        Overlay::path(vec![Point::new(0.0, 5.0), Point::new(20.0, 5.0)])
+            .with_color([0, 0, 0, 128])
+            .with_size(2.0)
+            .draw(&mut image, project);
+        assert_eq!(image.get_pixel(15, 4).0, [127, 127, 127, 255]);
+        assert_eq!(image.get_pixel(15, 7).0, WHITE);
+    }
+}
@@ -0,0 +1,59 @@
+use image::{Rgba, RgbaImage};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Placeholder {
+    Solid([u8; 4]),
+    Checkerboard {
+        colors: ([u8; 4], [u8; 4]),
+        cell_size: u32,
+    },
+}
+
+impl Placeholder {
+    pub(crate) fn to_image(&self, size: u32) -> RgbaImage {
+        match self {
+            Placeholder::Solid(color) => RgbaImage::from_pixel(size, size, Rgba(*color)),
+            Placeholder::Checkerboard { colors, cell_size } => {
+                let cell_size = (*cell_size).max(1);
+                RgbaImage::from_fn(size, size, |x, y| {
+                    if (x / cell_size + y / cell_size) % 2 == 0 {
+                        Rgba(colors.0)
+                    } else {
+                        Rgba(colors.1)
+                    }
+                })
+            }
+        }
+    }
+}
+
+impl Default for Placeholder {
+    fn default() -> Self {
+        Placeholder::Checkerboard {
+            colors: ([224, 224, 224, 255], [200, 200, 200, 255]),
+            cell_size: 16,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let image = Placeholder::Solid([1, 2, 3, 255]).to_image(4);
+        assert_eq!(image.dimensions(), (4, 4));
+        assert!(image.pixels().all(|p| p.0 == [1, 2, 3, 255]));
+
+        let image = Placeholder::Checkerboard {
+            colors: ([0, 0, 0, 255], [255, 255, 255, 255]),
+            cell_size: 2,
+        }
+        .to_image(4);
+        assert_eq!(image.get_pixel(0, 0).0, [0, 0, 0, 255]);
+        assert_eq!(image.get_pixel(1, 1).0, [0, 0, 0, 255]);
+        assert_eq!(image.get_pixel(2, 0).0, [255, 255, 255, 255]);
+        assert_eq!(image.get_pixel(2, 2).0, [0, 0, 0, 255]);
+    }
+}
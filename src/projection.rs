@@ -0,0 +1,91 @@
+use crate::{tile_id::TileId, view::TILE_SIZE};
+use geo::Point;
+use std::f64::consts::PI;
+
+/// Latitudes beyond this are cut off, they make the world square.
+pub const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+/// Web Mercator position of `point` in pixels from the top left corner of a world that
+/// is `256 * 2^zoom` pixels wide. Longitudes outside of -180..180 are wrapped around it.
+pub fn lnglat_to_world_pixel(point: &Point<f64>, zoom: f64) -> Point<f64> {
+    let world_size = TILE_SIZE * 2f64.powf(zoom);
+    let lat = point.lat().clamp(-MAX_LATITUDE, MAX_LATITUDE);
+    let x = world_size * (wrap_longitude(point.lng()) / 360.0 + 0.5);
+    let y = world_size * (1.0 - ((PI * (0.25 + lat / 360.0)).tan().ln()) / PI) / 2.0;
+    Point::new(x, y)
+}
+
+pub fn world_pixel_to_lnglat(pixel: &Point<f64>, zoom: f64) -> Point<f64> {
+    let world_size = TILE_SIZE * 2f64.powf(zoom);
+    let lng = pixel.x() / world_size * 360.0 - 180.0;
+    let lat = (PI * (1.0 - 2.0 * pixel.y() / world_size))
+        .sinh()
+        .atan()
+        .to_degrees();
+    Point::new(lng, lat)
+}
+
+/// Longitude moved into -180..180, the world repeats horizontally.
+pub fn wrap_longitude(lng: f64) -> f64 {
+    (lng + 180.0).rem_euclid(360.0) - 180.0
+}
+
+/// Tile of zoom level `zoom` that contains `point`, levels past `TileId::MAX_ZOOM` are
+/// clamped to it.
+pub fn tile_for_lnglat(point: &Point<f64>, zoom: u8) -> TileId {
+    let zoom = zoom.min(TileId::MAX_ZOOM);
+    let pixel = lnglat_to_world_pixel(point, f64::from(zoom));
+    let max = ((1u32 << zoom) - 1) as f64;
+    TileId::new(
+        (pixel.x() / TILE_SIZE).floor().clamp(0.0, max) as u32,
+        (pixel.y() / TILE_SIZE).floor().clamp(0.0, max) as u32,
+        zoom,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(
+            lnglat_to_world_pixel(&Point::new(0.0, 0.0), 1.0),
+            Point::new(256.0, 256.0)
+        );
+        let corner = lnglat_to_world_pixel(&Point::new(-180.0, 90.0), 0.0);
+        assert_eq!(corner.x(), 0.0);
+        assert!(corner.y().abs() < 0.01);
+
+        let helsinki = Point::new(24.945831, 60.19206);
+        let pixel = lnglat_to_world_pixel(&helsinki, 10.0);
+        let back = world_pixel_to_lnglat(&pixel, 10.0);
+        assert!((back.lng() - helsinki.lng()).abs() < 1e-9);
+        assert!((back.lat() - helsinki.lat()).abs() < 1e-9);
+
+        assert_eq!(wrap_longitude(190.0), -170.0);
+        assert_eq!(wrap_longitude(-540.0), -180.0);
+        assert_eq!(tile_for_lnglat(&helsinki, 10), TileId::new(582, 296, 10));
+        assert_eq!(
+            tile_for_lnglat(&Point::new(179.9, -90.0), 2),
+            TileId::new(3, 3, 2)
+        );
+        // East of the antimeridian the world starts over
+        assert_eq!(
+            lnglat_to_world_pixel(&Point::new(190.0, 0.0), 0.0),
+            lnglat_to_world_pixel(&Point::new(-170.0, 0.0), 0.0)
+        );
+        assert_eq!(
+            tile_for_lnglat(&Point::new(190.0, 0.0), 2),
+            TileId::new(0, 2, 2)
+        );
+        assert_eq!(
+            tile_for_lnglat(&Point::new(180.0, -90.0), 2),
+            TileId::new(0, 3, 2)
+        );
+        assert_eq!(
+            tile_for_lnglat(&Point::new(0.0, 0.0), 255).z(),
+            TileId::MAX_ZOOM
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use geo::Point;
+
+/// Looks at the world from above, positions are in pixels of the tile zoom level.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Camera {
+    pub x: f64,
+    pub y: f64,
+    /// Screen pixels per world pixel, differs from 1 on fractional zoom.
+    pub scale: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Camera {
+    pub fn new(x: f64, y: f64, scale: f64, width: f64, height: f64) -> Self {
+        Self {
+            x,
+            y,
+            scale,
+            width,
+            height,
+        }
+    }
+
+    pub fn world_to_screen(&self, world: &Point<f64>) -> Point<f64> {
+        Point::new(
+            (world.x() - self.x) * self.scale + self.width / 2.0,
+            (world.y() - self.y) * self.scale + self.height / 2.0,
+        )
+    }
+
+    #[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+    pub fn screen_to_world(&self, screen: &Point<f64>) -> Point<f64> {
+        Point::new(
+            (screen.x() - self.width / 2.0) / self.scale + self.x,
+            (screen.y() - self.height / 2.0) / self.scale + self.y,
+        )
+    }
+
+    /// Column major matrix that maps world pixels relative to `origin` to clip space. The
+    /// GPU only works in f32, which is precise enough close to the camera only.
+    #[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+    pub fn view_proj(&self, origin: (f64, f64)) -> [[f32; 4]; 4] {
+        let sx = 2.0 * self.scale / self.width;
+        let sy = -2.0 * self.scale / self.height;
+        [
+            [sx as f32, 0.0, 0.0, 0.0],
+            [0.0, sy as f32, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [
+                ((origin.0 - self.x) * sx) as f32,
+                ((origin.1 - self.y) * sy) as f32,
+                0.0,
+                1.0,
+            ],
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Clip space position of `x`, `y` rounded to hide f32 noise.
+    fn project(m: &[[f32; 4]; 4], x: f32, y: f32) -> (f32, f32) {
+        let round = |v: f32| (v * 1e4).round() / 1e4;
+        (
+            round(m[0][0] * x + m[1][0] * y + m[3][0]),
+            round(m[0][1] * x + m[1][1] * y + m[3][1]),
+        )
+    }
+
+    #[test]
+    fn it_works() {
+        let camera = Camera::new(1000.0, 500.0, 2.0, 800.0, 600.0);
+        let m = camera.view_proj((0.0, 0.0));
+        assert_eq!(project(&m, 1000.0, 500.0), (0.0, 0.0));
+        assert_eq!(project(&m, 800.0, 350.0), (-1.0, 1.0));
+        assert_eq!(project(&m, 1200.0, 650.0), (1.0, -1.0));
+
+        let screen = camera.world_to_screen(&Point::new(800.0, 350.0));
+        assert_eq!(screen, Point::new(0.0, 0.0));
+        assert_eq!(camera.screen_to_world(&screen), Point::new(800.0, 350.0));
+
+        // Far away from the origin of the world, f32 can't hold the positions themselves
+        let camera = Camera::new(67_108_864.25, 0.0, 1.0, 800.0, 600.0);
+        let m = camera.view_proj((67_108_000.0, 0.0));
+        assert_eq!(project(&m, 864.25, 0.0), (0.0, 0.0));
+        assert_eq!(project(&m, 464.25, 0.0), (-1.0, 0.0));
+    }
+}
@@ -1,8 +1,9 @@
-use std::time::Instant;
+use std::{collections::HashMap, time::Instant};
 
-use super::{texture::Texture, vertex::Vertex};
-use crate::tile::Tile;
+use super::{texture::Texture, texture_pool::TexturePool, vertex::Vertex};
+use crate::{tile::Tile, tile_coordinates::TileCoordinates, tile_id::TileId};
 use eyre::Result;
+use image::RgbaImage;
 use log::debug;
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
@@ -11,83 +12,184 @@ use wgpu::{
 };
 
 const INDICES: &[u16] = &[0, 1, 3, 1, 2, 3];
+/// How far in world pixels the tiles may get from the origin before the vertices are
+/// rebuilt around a new one, f32 keeps sub pixel precision up to here.
+const MAX_ORIGIN_DISTANCE: f64 = 65536.0;
 
+pub(crate) struct GridTexture {
+    // Kept alive for the bind group
+    _texture: Texture,
+    pub bind_group: BindGroup,
+}
+
+/// A quad is identified by the tile it covers, the tile its texture comes from, `None` for
+/// the placeholder, and its left edge, as the world repeats horizontally.
+type QuadKey = (TileId, Option<TileId>, u64);
+
+pub(crate) struct Quad {
+    key: QuadKey,
+    pub vertex_buffer: Buffer,
+}
+
+impl Quad {
+    pub fn texture_id(&self) -> Option<&TileId> {
+        self.key.1.as_ref()
+    }
+}
+
+/// Quads of the visible tiles in world space. Vertex buffers of tiles that stay visible are
+/// kept between updates and textures stay in the pool, so only new tiles are uploaded.
+/// Vertex positions are relative to `origin`, which stays close to the visible tiles.
 pub(crate) struct Grid {
-    pub bind_groups: Vec<BindGroup>,
-    pub textures: Vec<Texture>,
-    pub vertex_buffers: Vec<Buffer>,
+    pub origin: (f64, f64),
+    pub textures: TexturePool<GridTexture>,
+    pub placeholder: Option<GridTexture>,
+    pub quads: Vec<Quad>,
     pub index_buffer: Buffer,
     pub num_indices: u32,
 }
 
 impl Grid {
-    pub fn new(
-        device: &Device,
-        queue: &Queue,
-        bind_group_layout: &BindGroupLayout,
-        tiles: &[Tile],
-    ) -> Result<Self> {
-        let now = Instant::now();
-        let mut bind_groups = Vec::with_capacity(tiles.len());
-        let mut textures = Vec::with_capacity(tiles.len());
-        let mut vertex_buffers = Vec::with_capacity(tiles.len());
-
-        for tile in tiles {
-            let (texture, bind_group) =
-                Grid::create_texture_and_bind_group(device, queue, bind_group_layout, tile)?;
-            bind_groups.push(bind_group);
-            textures.push(texture);
-            let coords = tile.coords();
-            let vertices = vec![
-                Vertex {
-                    position: [coords.shader_coords.2, coords.shader_coords.1, 0.0],
-                    tex_coords: [coords.texture_coords.2, coords.texture_coords.1],
-                }, // B
-                Vertex {
-                    position: [coords.shader_coords.0, coords.shader_coords.1, 0.0],
-                    tex_coords: [coords.texture_coords.0, coords.texture_coords.1],
-                }, // A
-                Vertex {
-                    position: [coords.shader_coords.0, coords.shader_coords.3, 0.0],
-                    tex_coords: [coords.texture_coords.0, coords.texture_coords.3],
-                }, // C
-                Vertex {
-                    position: [coords.shader_coords.2, coords.shader_coords.3, 0.0],
-                    tex_coords: [coords.texture_coords.2, coords.texture_coords.3],
-                }, // D
-            ];
-            let vertex_buffer = device.create_buffer_init(&BufferInitDescriptor {
-                label: None,
-                contents: bytemuck::cast_slice(&vertices),
-                usage: BufferUsage::VERTEX,
-            });
-            vertex_buffers.push(vertex_buffer);
-            // break;
-        }
+    pub fn new(device: &Device) -> Self {
         let index_buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: None,
             contents: bytemuck::cast_slice(INDICES),
             usage: BufferUsage::INDEX,
         });
         let num_indices = INDICES.len() as u32;
-        debug!("New grid took {} ms", now.elapsed().as_millis());
-        Ok(Self {
-            bind_groups,
-            textures,
-            vertex_buffers,
+        Self {
+            origin: (0.0, 0.0),
+            textures: TexturePool::default(),
+            placeholder: None,
+            quads: Vec::new(),
             index_buffer,
             num_indices,
-        })
+        }
     }
 
-    fn create_texture_and_bind_group(
+    pub fn update(
+        &mut self,
         device: &Device,
         queue: &Queue,
         bind_group_layout: &BindGroupLayout,
-        tile: &Tile,
-    ) -> Result<(Texture, BindGroup)> {
-        let texture = Texture::from_bytes(device, queue, tile.data())?;
+        tiles: &[Tile],
+        placeholder: &RgbaImage,
+    ) -> Result<()> {
+        let now = Instant::now();
+        let mut old_quads: HashMap<QuadKey, Quad> = self
+            .quads
+            .drain(..)
+            .map(|quad| (quad.key.clone(), quad))
+            .collect();
+        if let Some(tile) = tiles.first() {
+            let (left, top, _, _) = tile.coords().shader_coords;
+            if (left - self.origin.0).abs() > MAX_ORIGIN_DISTANCE
+                || (top - self.origin.1).abs() > MAX_ORIGIN_DISTANCE
+            {
+                self.origin = (left, top);
+                old_quads.clear();
+            }
+        }
+        let mut uploaded = 0;
+        self.textures.begin_frame();
 
+        for tile in tiles {
+            let key = (
+                tile.id().clone(),
+                tile.data().map(|_| tile.data_id().clone()),
+                tile.coords().shader_coords.0.to_bits(),
+            );
+            match (tile.data(), &key.1) {
+                (Some(data), Some(data_id)) => {
+                    if !self.textures.touch(data_id) {
+                        uploaded += 1;
+                        let texture = Texture::from_rgba(device, queue, data, None)?;
+                        let texture = Grid::create_texture(device, bind_group_layout, texture);
+                        self.textures.insert(data_id.clone(), texture);
+                    }
+                }
+                _ => {
+                    if self.placeholder.is_none() {
+                        let texture =
+                            Texture::from_rgba(device, queue, placeholder, Some("placeholder"))?;
+                        self.placeholder =
+                            Some(Grid::create_texture(device, bind_group_layout, texture));
+                    }
+                }
+            }
+
+            let quad = match old_quads.remove(&key) {
+                Some(quad) => quad,
+                None => Quad {
+                    vertex_buffer: self.create_vertex_buffer(device, tile.coords()),
+                    key,
+                },
+            };
+            self.quads.push(quad);
+        }
+
+        debug!(
+            "Grid update uploaded {} of {} tiles in {} ms, {} textures on the GPU",
+            uploaded,
+            tiles.len(),
+            now.elapsed().as_millis(),
+            self.textures.len()
+        );
+        Ok(())
+    }
+
+    /// Drops the placeholder texture so the next update uploads a new one.
+    pub fn reset_placeholder(&mut self) {
+        self.placeholder = None;
+    }
+
+    pub fn bind_group(&self, quad: &Quad) -> Option<&BindGroup> {
+        match quad.texture_id() {
+            Some(id) => self.textures.get(id).map(|t| &t.bind_group),
+            None => self.placeholder.as_ref().map(|t| &t.bind_group),
+        }
+    }
+
+    fn create_vertex_buffer(&self, device: &Device, coords: &TileCoordinates) -> Buffer {
+        let (left, top, right, bottom) = coords.shader_coords;
+        let (left, right) = (
+            (left - self.origin.0) as f32,
+            (right - self.origin.0) as f32,
+        );
+        let (top, bottom) = (
+            (top - self.origin.1) as f32,
+            (bottom - self.origin.1) as f32,
+        );
+        let vertices = vec![
+            Vertex {
+                position: [right, top, 0.0],
+                tex_coords: [coords.texture_coords.2, coords.texture_coords.1],
+            }, // B
+            Vertex {
+                position: [left, top, 0.0],
+                tex_coords: [coords.texture_coords.0, coords.texture_coords.1],
+            }, // A
+            Vertex {
+                position: [left, bottom, 0.0],
+                tex_coords: [coords.texture_coords.0, coords.texture_coords.3],
+            }, // C
+            Vertex {
+                position: [right, bottom, 0.0],
+                tex_coords: [coords.texture_coords.2, coords.texture_coords.3],
+            }, // D
+        ];
+        device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&vertices),
+            usage: BufferUsage::VERTEX,
+        })
+    }
+
+    fn create_texture(
+        device: &Device,
+        bind_group_layout: &BindGroupLayout,
+        texture: Texture,
+    ) -> GridTexture {
         let bind_group = device.create_bind_group(&BindGroupDescriptor {
             layout: bind_group_layout,
             entries: &[
@@ -102,7 +204,9 @@ impl Grid {
             ],
             label: None,
         });
-
-        Ok((texture, bind_group))
+        GridTexture {
+            _texture: texture,
+            bind_group,
+        }
     }
 }
@@ -1,9 +1,29 @@
+mod camera;
+#[cfg(feature = "gpu")]
 mod grid;
+#[cfg(feature = "gpu")]
 mod painter;
+#[cfg(feature = "gpu")]
 mod pipeline;
+mod renderer;
+mod software;
+#[cfg(feature = "gpu")]
 mod texture;
+#[cfg(feature = "gpu")]
+mod texture_pool;
+#[cfg(feature = "gpu")]
 mod vertex;
 
+pub(crate) use camera::Camera;
+#[cfg(feature = "gpu")]
 pub(crate) use painter::Painter;
+#[cfg(feature = "gpu")]
 use pipeline::Pipeline;
+pub use renderer::Backend;
+pub(crate) use renderer::Renderer;
+pub(crate) use software::SoftwareRenderer;
+#[cfg(feature = "gpu")]
 use vertex::Vertex;
+
+/// Background where no tile is drawn, in linear color space.
+pub(crate) const CLEAR_COLOR: [f32; 4] = [0.1, 0.2, 0.3, 1.0];
@@ -1,45 +1,130 @@
 use std::time::Instant;
 
-use super::{grid::Grid, Pipeline};
-use crate::tile::Tile;
-use eyre::Result;
+use super::{grid::Grid, Camera, Pipeline, CLEAR_COLOR};
+use crate::{placeholder::Placeholder, tile::Tile};
+use eyre::{eyre, Result};
+use image::RgbaImage;
 use log::debug;
 use wgpu::{
-    BackendBit, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType,
-    Color, CommandEncoderDescriptor, Device, DeviceDescriptor, Features, Instance, Limits, LoadOp,
-    Operations, PowerPreference, PresentMode, Queue, RenderPassColorAttachmentDescriptor,
-    RenderPassDescriptor, RequestAdapterOptions, ShaderStage, Surface, SwapChain,
-    SwapChainDescriptor, TextureComponentType, TextureFormat, TextureUsage, TextureViewDimension,
+    util::{BufferInitDescriptor, DeviceExt},
+    BackendBit, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+    BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, Buffer,
+    BufferAddress, BufferCopyView, BufferDescriptor, BufferUsage, Color, CommandEncoder,
+    CommandEncoderDescriptor, Device, DeviceDescriptor, Extent3d, Features, Instance, Limits,
+    LoadOp, Maintain, MapMode, Operations, Origin3d, PowerPreference, PresentMode, Queue,
+    RenderPassColorAttachmentDescriptor, RenderPassDescriptor, RequestAdapterOptions, ShaderStage,
+    Surface, SwapChain, SwapChainDescriptor, Texture, TextureComponentType, TextureCopyView,
+    TextureDataLayout, TextureDescriptor, TextureDimension, TextureFormat, TextureUsage,
+    TextureView, TextureViewDescriptor, TextureViewDimension, COPY_BYTES_PER_ROW_ALIGNMENT,
 };
 use winit::window::Window;
 
+const OFFSCREEN_FORMAT: TextureFormat = TextureFormat::Rgba8UnormSrgb;
+
+enum Target {
+    Window {
+        surface: Surface,
+        swap_chain: SwapChain,
+        sc_desc: SwapChainDescriptor,
+    },
+    /// Rendering into a texture that is copied back to the CPU, no window needed.
+    Offscreen {
+        texture: Texture,
+        view: TextureView,
+        width: u32,
+        height: u32,
+    },
+}
+
 pub(crate) struct Painter {
     device: Device,
     queue: Queue,
-    swap_chain: SwapChain,
-    sc_desc: SwapChainDescriptor,
-    surface: Surface,
+    target: Target,
     pipeline: Pipeline,
     bind_group_layout: BindGroupLayout,
+    camera_buffer: Buffer,
+    camera_bind_group: BindGroup,
     grid: Grid,
+    camera: Camera,
+    placeholder: RgbaImage,
 }
 
 impl Painter {
-    pub async fn new(window: &Window, tiles: &[Tile]) -> Result<Self> {
+    pub async fn new(window: &Window, placeholder: &Placeholder, tile_size: u32) -> Result<Self> {
         let size = window.inner_size();
         let instance = Instance::new(BackendBit::PRIMARY);
         let surface = unsafe { instance.create_surface(window) };
+        // Request an adapter which can render to our surface
+        let (device, queue) = Painter::request_device(&instance, Some(&surface)).await?;
+
+        let sc_desc = SwapChainDescriptor {
+            usage: TextureUsage::OUTPUT_ATTACHMENT,
+            format: TextureFormat::Bgra8UnormSrgb,
+            width: size.width,
+            height: size.height,
+            present_mode: PresentMode::Mailbox,
+        };
+
+        let swap_chain = device.create_swap_chain(&surface, &sc_desc);
+        let target = Target::Window {
+            surface,
+            swap_chain,
+            sc_desc,
+        };
+
+        Painter::with_target(device, queue, target, placeholder, tile_size)
+    }
+
+    /// Renders into an offscreen texture of `width` x `height` pixels, read back with
+    /// `render_to_image`. Any adapter works, including software Vulkan implementations
+    /// like lavapipe or SwiftShader on machines without a GPU.
+    pub async fn headless(
+        width: u32,
+        height: u32,
+        placeholder: &Placeholder,
+        tile_size: u32,
+    ) -> Result<Self> {
+        let instance = Instance::new(BackendBit::PRIMARY);
+        let (device, queue) = Painter::request_device(&instance, None).await?;
+
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("offscreen"),
+            size: Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: OFFSCREEN_FORMAT,
+            usage: TextureUsage::OUTPUT_ATTACHMENT | TextureUsage::COPY_SRC,
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        let target = Target::Offscreen {
+            texture,
+            view,
+            width,
+            height,
+        };
+
+        Painter::with_target(device, queue, target, placeholder, tile_size)
+    }
+
+    async fn request_device(
+        instance: &Instance,
+        compatible_surface: Option<&Surface>,
+    ) -> Result<(Device, Queue)> {
         let adapter = instance
             .request_adapter(&RequestAdapterOptions {
                 power_preference: PowerPreference::default(),
-                // Request an adapter which can render to our surface
-                compatible_surface: Some(&surface),
+                compatible_surface,
             })
             .await
-            .expect("Failed to find an appropriate adapter");
+            .ok_or_else(|| eyre!("Failed to find an appropriate adapter"))?;
 
         // Create the logical device and command queue
-        let (device, queue) = adapter
+        let device = adapter
             .request_device(
                 &DeviceDescriptor {
                     features: Features::empty(),
@@ -49,17 +134,21 @@ impl Painter {
                 None,
             )
             .await?;
+        Ok(device)
+    }
 
-        let sc_desc = SwapChainDescriptor {
-            usage: TextureUsage::OUTPUT_ATTACHMENT,
-            format: TextureFormat::Bgra8UnormSrgb,
-            width: size.width,
-            height: size.height,
-            present_mode: PresentMode::Mailbox,
+    fn with_target(
+        device: Device,
+        queue: Queue,
+        target: Target,
+        placeholder: &Placeholder,
+        tile_size: u32,
+    ) -> Result<Self> {
+        let (format, width, height) = match &target {
+            Target::Window { sc_desc, .. } => (sc_desc.format, sc_desc.width, sc_desc.height),
+            Target::Offscreen { width, height, .. } => (OFFSCREEN_FORMAT, *width, *height),
         };
 
-        let swap_chain = device.create_swap_chain(&surface, &sc_desc);
-
         let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             entries: &[
                 BindGroupLayoutEntry {
@@ -82,69 +171,226 @@ impl Painter {
             label: Some("texture_bind_group_layout"),
         });
 
-        let grid = Grid::new(&device, &queue, &bind_group_layout, tiles)?;
-        let pipeline = Pipeline::new(&device, TextureFormat::Bgra8UnormSrgb, &bind_group_layout);
+        let camera_bind_group_layout =
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                entries: &[BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStage::VERTEX,
+                    ty: BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+                label: Some("camera_bind_group_layout"),
+            });
+        let camera = Camera::new(0.0, 0.0, 1.0, width as f64, height as f64);
+        let grid = Grid::new(&device);
+        let camera_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("camera"),
+            contents: bytemuck::cast_slice(&camera.view_proj(grid.origin)),
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+        });
+        let camera_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            layout: &camera_bind_group_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::Buffer(camera_buffer.slice(..)),
+            }],
+            label: Some("camera_bind_group"),
+        });
+
+        let placeholder = placeholder.to_image(tile_size);
+        let pipeline = Pipeline::new(
+            &device,
+            format,
+            &bind_group_layout,
+            &camera_bind_group_layout,
+        );
 
         Ok(Self {
             device,
             queue,
-            swap_chain,
-            surface,
-            sc_desc,
+            target,
             pipeline,
             bind_group_layout,
+            camera_buffer,
+            camera_bind_group,
             grid,
+            camera,
+            placeholder,
         })
     }
 
+    /// Uploads the tiles that are not on the GPU yet, the others are reused.
     pub fn load_textures(&mut self, tiles: &[Tile]) -> Result<()> {
         let now = Instant::now();
-        self.grid = Grid::new(&self.device, &self.queue, &self.bind_group_layout, tiles)?;
+        let origin = self.grid.origin;
+        self.grid.update(
+            &self.device,
+            &self.queue,
+            &self.bind_group_layout,
+            tiles,
+            &self.placeholder,
+        )?;
+        if self.grid.origin != origin {
+            self.write_camera();
+        }
         debug!("Load textures took {} ms", now.elapsed().as_millis());
         Ok(())
     }
 
+    /// Limits the tile textures kept on the GPU, the visible ones are always kept.
+    pub fn set_max_textures(&mut self, max_textures: usize) {
+        self.grid.textures.set_max_textures(max_textures);
+    }
+
+    pub fn set_camera(&mut self, camera: &Camera) {
+        self.camera = camera.clone();
+        self.write_camera();
+    }
+
+    fn write_camera(&self) {
+        self.queue.write_buffer(
+            &self.camera_buffer,
+            0,
+            bytemuck::cast_slice(&self.camera.view_proj(self.grid.origin)),
+        );
+    }
+
+    pub fn set_placeholder(&mut self, placeholder: &Placeholder, tile_size: u32) {
+        self.placeholder = placeholder.to_image(tile_size);
+        self.grid.reset_placeholder();
+    }
+
     pub fn render(&mut self) -> Result<()> {
-        let frame = match self.swap_chain.get_current_frame() {
+        let (surface, swap_chain, sc_desc) = match &mut self.target {
+            Target::Window {
+                surface,
+                swap_chain,
+                sc_desc,
+            } => (surface, swap_chain, sc_desc),
+            Target::Offscreen { .. } => {
+                return Err(eyre!("Offscreen painters render with render_to_image"))
+            }
+        };
+        let frame = match swap_chain.get_current_frame() {
             Ok(frame) => frame,
             Err(_) => {
-                self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
-                self.swap_chain.get_current_frame()?
+                *swap_chain = self.device.create_swap_chain(surface, sc_desc);
+                swap_chain.get_current_frame()?
             }
         };
 
         let mut encoder = self
             .device
             .create_command_encoder(&CommandEncoderDescriptor { label: None });
+        self.draw(&mut encoder, &frame.output.view);
+        self.queue.submit(Some(encoder.finish()));
+
+        Ok(())
+    }
+
+    /// Draws the offscreen target and copies it back into an image.
+    pub async fn render_to_image(&mut self) -> Result<RgbaImage> {
+        let (texture, view, width, height) = match &self.target {
+            Target::Offscreen {
+                texture,
+                view,
+                width,
+                height,
+            } => (texture, view, *width, *height),
+            Target::Window { .. } => return Err(eyre!("Window painters render with render")),
+        };
+
+        // Rows of a texture copy have to be aligned
+        let bytes_per_row = 4 * width;
+        let padding = (COPY_BYTES_PER_ROW_ALIGNMENT - bytes_per_row % COPY_BYTES_PER_ROW_ALIGNMENT)
+            % COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = bytes_per_row + padding;
+        let buffer = self.device.create_buffer(&BufferDescriptor {
+            label: Some("offscreen_readback"),
+            size: (padded_bytes_per_row * height) as BufferAddress,
+            usage: BufferUsage::COPY_DST | BufferUsage::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        // The encoder is not Send, so it must be gone before the first await
         {
-            let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
-                color_attachments: &[RenderPassColorAttachmentDescriptor {
-                    attachment: &frame.output.view,
-                    resolve_target: None,
-                    ops: Operations {
-                        load: LoadOp::Clear(Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
-                        }),
-                        store: true,
+            let mut encoder = self
+                .device
+                .create_command_encoder(&CommandEncoderDescriptor { label: None });
+            self.draw(&mut encoder, view);
+            encoder.copy_texture_to_buffer(
+                TextureCopyView {
+                    texture,
+                    mip_level: 0,
+                    origin: Origin3d::ZERO,
+                },
+                BufferCopyView {
+                    buffer: &buffer,
+                    layout: TextureDataLayout {
+                        offset: 0,
+                        bytes_per_row: padded_bytes_per_row,
+                        rows_per_image: height,
                     },
-                }],
-                depth_stencil_attachment: None,
-            });
+                },
+                Extent3d {
+                    width,
+                    height,
+                    depth: 1,
+                },
+            );
+            self.queue.submit(Some(encoder.finish()));
+        }
+
+        let slice = buffer.slice(..);
+        let mapping = slice.map_async(MapMode::Read);
+        self.device.poll(Maintain::Wait);
+        mapping.await?;
 
-            render_pass.set_pipeline(self.pipeline.get());
-            for (index, bind_group) in self.grid.bind_groups.iter().enumerate() {
-                render_pass.set_bind_group(0, &bind_group, &[]);
-                render_pass.set_vertex_buffer(0, self.grid.vertex_buffers[index].slice(..));
-                render_pass.set_index_buffer(self.grid.index_buffer.slice(..));
-                render_pass.draw_indexed(0..self.grid.num_indices, 0, 0..1);
+        let mut pixels = Vec::with_capacity((bytes_per_row * height) as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks(padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..bytes_per_row as usize]);
             }
         }
+        buffer.unmap();
 
-        self.queue.submit(Some(encoder.finish()));
+        RgbaImage::from_raw(width, height, pixels)
+            .ok_or_else(|| eyre!("Offscreen image has an unexpected size"))
+    }
 
-        Ok(())
+    fn draw(&self, encoder: &mut CommandEncoder, view: &TextureView) {
+        let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+            color_attachments: &[RenderPassColorAttachmentDescriptor {
+                attachment: view,
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Clear(Color {
+                        r: CLEAR_COLOR[0] as f64,
+                        g: CLEAR_COLOR[1] as f64,
+                        b: CLEAR_COLOR[2] as f64,
+                        a: CLEAR_COLOR[3] as f64,
+                    }),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_pipeline(self.pipeline.get());
+        render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
+        for quad in &self.grid.quads {
+            let bind_group = match self.grid.bind_group(quad) {
+                Some(bind_group) => bind_group,
+                None => continue,
+            };
+            render_pass.set_bind_group(0, bind_group, &[]);
+            render_pass.set_vertex_buffer(0, quad.vertex_buffer.slice(..));
+            render_pass.set_index_buffer(self.grid.index_buffer.slice(..));
+            render_pass.draw_indexed(0..self.grid.num_indices, 0, 0..1);
+        }
     }
 }
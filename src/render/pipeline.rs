@@ -16,10 +16,11 @@ impl Pipeline {
         device: &Device,
         format: TextureFormat,
         texture_bind_group_layout: &BindGroupLayout,
+        camera_bind_group_layout: &BindGroupLayout,
     ) -> Self {
         let render_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
             label: Some("Render Pipeline Layout"),
-            bind_group_layouts: &[texture_bind_group_layout],
+            bind_group_layouts: &[texture_bind_group_layout, camera_bind_group_layout],
             push_constant_ranges: &[],
         });
 
@@ -0,0 +1,162 @@
+#[cfg(feature = "gpu")]
+use super::Painter;
+use super::{Camera, SoftwareRenderer};
+use crate::{placeholder::Placeholder, tile::Tile};
+use eyre::Result;
+use futures::future::BoxFuture;
+use image::RgbaImage;
+use log::warn;
+
+/// Which renderer draws offscreen maps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// wgpu, falls back to `Software` when no adapter is available.
+    Auto,
+    /// wgpu, needs the `gpu` feature.
+    Gpu,
+    /// Composites the tiles on the CPU, no GPU or driver needed.
+    Software,
+}
+
+impl Default for Backend {
+    /// `Software` with the `software` feature, `Auto` otherwise.
+    fn default() -> Self {
+        if cfg!(feature = "software") {
+            Backend::Software
+        } else {
+            Backend::Auto
+        }
+    }
+}
+
+impl Backend {
+    pub(crate) async fn create(
+        self,
+        width: u32,
+        height: u32,
+        placeholder: &Placeholder,
+        tile_size: u32,
+    ) -> Result<Box<dyn Renderer>> {
+        let software = || SoftwareRenderer::new(width, height, placeholder, tile_size);
+        let renderer: Box<dyn Renderer> = match self {
+            Backend::Gpu => Backend::gpu(width, height, placeholder, tile_size).await?,
+            Backend::Software => Box::new(software()),
+            Backend::Auto => match Backend::gpu(width, height, placeholder, tile_size).await {
+                Ok(renderer) => renderer,
+                Err(e) => {
+                    warn!("Falling back to the software renderer: {}", e);
+                    Box::new(software())
+                }
+            },
+        };
+        Ok(renderer)
+    }
+
+    #[cfg(feature = "gpu")]
+    async fn gpu(
+        width: u32,
+        height: u32,
+        placeholder: &Placeholder,
+        tile_size: u32,
+    ) -> Result<Box<dyn Renderer>> {
+        Ok(Box::new(
+            Painter::headless(width, height, placeholder, tile_size).await?,
+        ))
+    }
+
+    #[cfg(not(feature = "gpu"))]
+    async fn gpu(
+        _width: u32,
+        _height: u32,
+        _placeholder: &Placeholder,
+        _tile_size: u32,
+    ) -> Result<Box<dyn Renderer>> {
+        Err(eyre::eyre!("Built without the gpu feature"))
+    }
+}
+
+/// Draws tiles into an image, implemented on the GPU by `Painter` and on the CPU by
+/// `SoftwareRenderer`.
+pub(crate) trait Renderer: Send {
+    fn load_textures(&mut self, tiles: &[Tile]) -> Result<()>;
+
+    fn set_camera(&mut self, camera: &Camera);
+
+    fn set_placeholder(&mut self, placeholder: &Placeholder, tile_size: u32);
+
+    fn render_to_image(&mut self) -> BoxFuture<'_, Result<RgbaImage>>;
+}
+
+#[cfg(feature = "gpu")]
+impl Renderer for Painter {
+    fn load_textures(&mut self, tiles: &[Tile]) -> Result<()> {
+        Painter::load_textures(self, tiles)
+    }
+
+    fn set_camera(&mut self, camera: &Camera) {
+        Painter::set_camera(self, camera)
+    }
+
+    fn set_placeholder(&mut self, placeholder: &Placeholder, tile_size: u32) {
+        Painter::set_placeholder(self, placeholder, tile_size)
+    }
+
+    fn render_to_image(&mut self) -> BoxFuture<'_, Result<RgbaImage>> {
+        Box::pin(Painter::render_to_image(self))
+    }
+}
+
+#[cfg(all(test, feature = "gpu"))]
+mod tests {
+    use super::*;
+    use crate::{tile_coordinates::TileCoordinates, tile_id::TileId};
+    use image::Rgba;
+    use std::sync::Arc;
+
+    fn render(renderer: &mut dyn Renderer, tiles: &[Tile], camera: &Camera) -> RgbaImage {
+        renderer.load_textures(tiles).unwrap();
+        renderer.set_camera(camera);
+        futures::executor::block_on(renderer.render_to_image()).unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter, run with --ignored"]
+    fn software_matches_gpu() {
+        let (width, height) = (64, 48);
+        let placeholder = Placeholder::Solid([0, 0, 255, 255]);
+        let mut painter =
+            futures::executor::block_on(Painter::headless(width, height, &placeholder, 16))
+                .expect("No GPU adapter");
+        let mut software = SoftwareRenderer::new(width, height, &placeholder, 16);
+
+        // A magnified gradient next to a placeholder, with the clear color below
+        let texture = RgbaImage::from_fn(16, 16, |x, y| {
+            Rgba([(x * 16) as u8, (y * 16) as u8, 128, 255])
+        });
+        let tiles = vec![
+            Tile::new(
+                &TileId::new(0, 0, 1),
+                Some(Arc::new(texture)),
+                &TileCoordinates::new(0.0, 0.0, 32.0),
+            ),
+            Tile::new(
+                &TileId::new(1, 0, 1),
+                None,
+                &TileCoordinates::new(32.0, 0.0, 32.0),
+            ),
+        ];
+        let camera = Camera::new(32.0, 20.0, 1.5, width as f64, height as f64);
+
+        let expected = render(&mut painter, &tiles, &camera);
+        let actual = render(&mut software, &tiles, &camera);
+        for (x, y, pixel) in actual.enumerate_pixels() {
+            let gpu = expected.get_pixel(x, y);
+            let close = pixel
+                .0
+                .iter()
+                .zip(&gpu.0)
+                .all(|(a, b)| (*a as i32 - *b as i32).abs() <= 2);
+            assert!(close, "Pixel {}, {}: {:?} != {:?}", x, y, pixel, gpu);
+        }
+    }
+}
@@ -5,7 +5,12 @@ layout(location=1) in vec2 a_tex_coords;
 
 layout(location=0) out vec2 v_tex_coords;
 
+layout(set=1, binding=0)
+uniform Camera {
+    mat4 u_view_proj;
+};
+
 void main() {
     v_tex_coords = a_tex_coords;
-    gl_Position = vec4(a_position, 1.0);
+    gl_Position = u_view_proj * vec4(a_position, 1.0);
 }
\ No newline at end of file
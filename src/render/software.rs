@@ -0,0 +1,223 @@
+use std::time::Instant;
+
+use super::{Camera, Renderer, CLEAR_COLOR};
+use crate::{placeholder::Placeholder, tile::Tile};
+use eyre::Result;
+use futures::future::{self, BoxFuture};
+use image::{Rgba, RgbaImage};
+use log::debug;
+
+/// Composites the tiles on the CPU the way the wgpu pipeline does: pixel centres inside a
+/// quad are covered, magnified textures are filtered linearly in linear color space and
+/// minified ones sampled nearest, matching the tile sampler. Results differ from the GPU
+/// by rounding only.
+pub(crate) struct SoftwareRenderer {
+    width: u32,
+    height: u32,
+    tiles: Vec<Tile>,
+    camera: Camera,
+    placeholder: RgbaImage,
+    to_linear: [f32; 256],
+}
+
+impl SoftwareRenderer {
+    pub fn new(width: u32, height: u32, placeholder: &Placeholder, tile_size: u32) -> Self {
+        let mut to_linear = [0.0; 256];
+        for (i, value) in to_linear.iter_mut().enumerate() {
+            *value = srgb_to_linear(i as f32 / 255.0);
+        }
+        Self {
+            width,
+            height,
+            tiles: Vec::new(),
+            camera: Camera::new(0.0, 0.0, 1.0, width as f64, height as f64),
+            placeholder: placeholder.to_image(tile_size),
+            to_linear,
+        }
+    }
+
+    fn draw(&self) -> RgbaImage {
+        let now = Instant::now();
+        let clear = [
+            linear_to_srgb(CLEAR_COLOR[0]),
+            linear_to_srgb(CLEAR_COLOR[1]),
+            linear_to_srgb(CLEAR_COLOR[2]),
+            (CLEAR_COLOR[3] * 255.0).round() as u8,
+        ];
+        let mut image = RgbaImage::from_pixel(self.width, self.height, Rgba(clear));
+        for tile in &self.tiles {
+            self.draw_tile(&mut image, tile);
+        }
+        debug!("Software render took {} ms", now.elapsed().as_millis());
+        image
+    }
+
+    fn draw_tile(&self, image: &mut RgbaImage, tile: &Tile) {
+        let texture = tile.data().unwrap_or(&self.placeholder);
+        let (left, top, right, bottom) = tile.coords().shader_coords;
+        let (tex_left, tex_top, tex_right, tex_bottom) = tile.coords().texture_coords;
+
+        // Screen pixels of the quad corners, the camera size may be in logical pixels
+        let camera = &self.camera;
+        let scale_x = camera.scale * self.width as f64 / camera.width;
+        let scale_y = camera.scale * self.height as f64 / camera.height;
+        let to_screen_x = |x: f64| ((x - camera.x) * scale_x + self.width as f64 / 2.0) as f32;
+        let to_screen_y = |y: f64| ((y - camera.y) * scale_y + self.height as f64 / 2.0) as f32;
+        let (screen_left, screen_right) = (to_screen_x(left), to_screen_x(right));
+        let (screen_top, screen_bottom) = (to_screen_y(top), to_screen_y(bottom));
+
+        let first_x = (screen_left - 0.5).ceil().max(0.0) as u32;
+        let end_x = (screen_right - 0.5).ceil().clamp(0.0, self.width as f32) as u32;
+        let first_y = (screen_top - 0.5).ceil().max(0.0) as u32;
+        let end_y = (screen_bottom - 0.5).ceil().clamp(0.0, self.height as f32) as u32;
+
+        let texels_per_pixel =
+            texture.width() as f32 * (tex_right - tex_left) / (screen_right - screen_left);
+        let magnified = texels_per_pixel <= 1.0;
+
+        for y in first_y..end_y {
+            let v = tex_top
+                + (y as f32 + 0.5 - screen_top) / (screen_bottom - screen_top)
+                    * (tex_bottom - tex_top);
+            for x in first_x..end_x {
+                let u = tex_left
+                    + (x as f32 + 0.5 - screen_left) / (screen_right - screen_left)
+                        * (tex_right - tex_left);
+                let color = if magnified {
+                    self.sample_linear(texture, u, v)
+                } else {
+                    sample_nearest(texture, u, v)
+                };
+                image.put_pixel(x, y, color);
+            }
+        }
+    }
+
+    fn sample_linear(&self, texture: &RgbaImage, u: f32, v: f32) -> Rgba<u8> {
+        let x = u * texture.width() as f32 - 0.5;
+        let y = v * texture.height() as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let texel = |dx: f32, dy: f32| {
+            let tx = (x0 + dx).clamp(0.0, texture.width() as f32 - 1.0) as u32;
+            let ty = (y0 + dy).clamp(0.0, texture.height() as f32 - 1.0) as u32;
+            texture.get_pixel(tx, ty).0
+        };
+        let corners = [
+            (texel(0.0, 0.0), (1.0 - fx) * (1.0 - fy)),
+            (texel(1.0, 0.0), fx * (1.0 - fy)),
+            (texel(0.0, 1.0), (1.0 - fx) * fy),
+            (texel(1.0, 1.0), fx * fy),
+        ];
+
+        let mut color = [0.0f32; 4];
+        for (texel, weight) in &corners {
+            for i in 0..3 {
+                color[i] += self.to_linear[texel[i] as usize] * weight;
+            }
+            color[3] += texel[3] as f32 / 255.0 * weight;
+        }
+        Rgba([
+            linear_to_srgb(color[0]),
+            linear_to_srgb(color[1]),
+            linear_to_srgb(color[2]),
+            (color[3] * 255.0).round() as u8,
+        ])
+    }
+}
+
+impl Renderer for SoftwareRenderer {
+    fn load_textures(&mut self, tiles: &[Tile]) -> Result<()> {
+        self.tiles = tiles.to_vec();
+        Ok(())
+    }
+
+    fn set_camera(&mut self, camera: &Camera) {
+        self.camera = camera.clone();
+    }
+
+    fn set_placeholder(&mut self, placeholder: &Placeholder, tile_size: u32) {
+        self.placeholder = placeholder.to_image(tile_size);
+    }
+
+    fn render_to_image(&mut self) -> BoxFuture<'_, Result<RgbaImage>> {
+        Box::pin(future::ready(Ok(self.draw())))
+    }
+}
+
+fn sample_nearest(texture: &RgbaImage, u: f32, v: f32) -> Rgba<u8> {
+    let x = (u * texture.width() as f32).floor();
+    let y = (v * texture.height() as f32).floor();
+    *texture.get_pixel(
+        x.clamp(0.0, texture.width() as f32 - 1.0) as u32,
+        y.clamp(0.0, texture.height() as f32 - 1.0) as u32,
+    )
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (value * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tile_coordinates::TileCoordinates, tile_id::TileId};
+    use std::sync::Arc;
+
+    fn render(renderer: &mut SoftwareRenderer, tiles: &[Tile]) -> RgbaImage {
+        renderer.load_textures(tiles).unwrap();
+        futures::executor::block_on(renderer.render_to_image()).unwrap()
+    }
+
+    #[test]
+    fn it_works() {
+        let placeholder = Placeholder::Solid([0, 0, 255, 255]);
+        let mut renderer = SoftwareRenderer::new(8, 8, &placeholder, 4);
+        // The world pixel (4, 4) is in the middle of the image
+        renderer.set_camera(&Camera::new(4.0, 4.0, 1.0, 8.0, 8.0));
+
+        let id = TileId::new(0, 0, 1);
+        let red = Arc::new(RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255])));
+        let tiles = vec![
+            Tile::new(&id, Some(red), &TileCoordinates::new(0.0, 0.0, 4.0)),
+            Tile::new(&id, None, &TileCoordinates::new(4.0, 0.0, 4.0)),
+        ];
+        let image = render(&mut renderer, &tiles);
+        assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(3, 3).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(4, 0).0, [0, 0, 255, 255]);
+        // Linear 0.1, 0.2, 0.3 in sRGB
+        assert_eq!(image.get_pixel(0, 4).0, [89, 124, 149, 255]);
+    }
+
+    #[test]
+    fn magnification_is_linear() {
+        let mut renderer = SoftwareRenderer::new(4, 1, &Placeholder::default(), 2);
+        renderer.set_camera(&Camera::new(1.0, 0.25, 2.0, 4.0, 1.0));
+
+        let id = TileId::new(0, 0, 1);
+        let texture = RgbaImage::from_fn(2, 1, |x, _| Rgba([(x * 255) as u8, 0, 0, 255]));
+        let tiles = vec![Tile::new(
+            &id,
+            Some(Arc::new(texture)),
+            &TileCoordinates::new(0.0, 0.0, 2.0),
+        )];
+        let image = render(&mut renderer, &tiles);
+        let reds: Vec<_> = image.pixels().map(|p| p.0[0]).collect();
+        // Clamped at the edges, blended in linear space in between
+        assert_eq!(reds, vec![0, 137, 225, 255]);
+    }
+}
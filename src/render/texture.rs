@@ -1,5 +1,5 @@
 use eyre::Result;
-use image::GenericImageView;
+use image::RgbaImage;
 
 pub(crate) struct Texture {
     // pub texture: wgpu::Texture,
@@ -8,19 +8,14 @@ pub(crate) struct Texture {
 }
 
 impl Texture {
-    pub fn from_bytes(device: &wgpu::Device, queue: &wgpu::Queue, bytes: &[u8]) -> Result<Self> {
-        let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, img, None)
-    }
-
-    pub fn from_image(
+    /// Uploads an already decoded image, decoding happens when tiles arrive.
+    pub fn from_rgba(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        img: image::DynamicImage,
+        rgba: &RgbaImage,
         label: Option<&str>,
     ) -> Result<Self> {
-        let dimensions = img.dimensions();
-        let rgba = img.into_rgba();
+        let dimensions = rgba.dimensions();
 
         let size = wgpu::Extent3d {
             width: dimensions.0,
@@ -43,7 +38,7 @@ impl Texture {
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
             },
-            &rgba,
+            rgba,
             wgpu::TextureDataLayout {
                 offset: 0,
                 bytes_per_row: 4 * dimensions.0,
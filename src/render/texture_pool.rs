@@ -0,0 +1,135 @@
+use crate::tile_id::TileId;
+use std::collections::{BTreeMap, HashMap};
+
+pub(crate) const DEFAULT_MAX_TEXTURES: usize = 256;
+
+struct PoolEntry<T> {
+    texture: T,
+    last_used: u64,
+}
+
+/// GPU resident textures by tile, the least recently used ones are dropped once the pool
+/// holds more than `max_textures`. Textures used since the last `begin_frame` are kept
+/// even over the limit.
+pub(crate) struct TexturePool<T> {
+    max_textures: usize,
+    entries: HashMap<TileId, PoolEntry<T>>,
+    lru: BTreeMap<u64, TileId>,
+    clock: u64,
+    frame_start: u64,
+}
+
+impl<T> TexturePool<T> {
+    pub fn new(max_textures: usize) -> Self {
+        Self {
+            max_textures,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            frame_start: 0,
+        }
+    }
+
+    pub fn begin_frame(&mut self) {
+        self.frame_start = self.clock + 1;
+    }
+
+    /// Marks the texture as used in this frame, returns `false` if it has to be uploaded.
+    pub fn touch(&mut self, id: &TileId) -> bool {
+        match self.entries.get_mut(id) {
+            Some(entry) => {
+                self.clock += 1;
+                self.lru.remove(&entry.last_used);
+                entry.last_used = self.clock;
+                self.lru.insert(self.clock, id.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, id: &TileId) -> Option<&T> {
+        self.entries.get(id).map(|entry| &entry.texture)
+    }
+
+    pub fn insert(&mut self, id: TileId, texture: T) {
+        self.clock += 1;
+        self.lru.insert(self.clock, id.clone());
+        let previous = self.entries.insert(
+            id,
+            PoolEntry {
+                texture,
+                last_used: self.clock,
+            },
+        );
+        if let Some(previous) = previous {
+            self.lru.remove(&previous.last_used);
+        }
+
+        self.evict();
+    }
+
+    pub fn set_max_textures(&mut self, max_textures: usize) {
+        self.max_textures = max_textures;
+        self.evict();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.max_textures {
+            let (last_used, id) = match self.lru.iter().next() {
+                Some((last_used, id)) if *last_used < self.frame_start => (*last_used, id.clone()),
+                _ => break,
+            };
+            self.lru.remove(&last_used);
+            self.entries.remove(&id);
+        }
+    }
+}
+
+impl<T> Default for TexturePool<T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_TEXTURES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(x: u32) -> TileId {
+        TileId::new(x, 0, 1)
+    }
+
+    #[test]
+    fn it_works() {
+        let mut pool = TexturePool::new(2);
+        pool.begin_frame();
+        pool.insert(tile(0), 0);
+        pool.insert(tile(1), 1);
+        pool.begin_frame();
+        assert!(pool.touch(&tile(0)));
+        assert!(!pool.touch(&tile(2)));
+        pool.insert(tile(2), 2);
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.get(&tile(0)), Some(&0));
+        assert_eq!(pool.get(&tile(1)), None);
+
+        // Everything is in use, the pool grows over the limit until the next frame
+        pool.begin_frame();
+        for x in 0..3 {
+            if !pool.touch(&tile(x)) {
+                pool.insert(tile(x), x);
+            }
+        }
+        assert_eq!(pool.len(), 3);
+
+        pool.begin_frame();
+        pool.set_max_textures(1);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.get(&tile(2)), Some(&2));
+    }
+}
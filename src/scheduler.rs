@@ -0,0 +1,265 @@
+use crate::tile_id::TileId;
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::oneshot;
+
+const DEFAULT_MAX_IN_FLIGHT: usize = 4;
+
+struct Waiter {
+    id: TileId,
+    priority: u32,
+    seq: u64,
+    sender: oneshot::Sender<Permit>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority && self.seq == other.seq
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    // BinaryHeap pops the greatest element, so lower priority values and older requests
+    // have to compare as greater
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .priority
+            .cmp(&self.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Default)]
+struct HostQueue {
+    in_flight: usize,
+    waiters: BinaryHeap<Waiter>,
+}
+
+#[derive(Default)]
+struct State {
+    hosts: HashMap<String, HostQueue>,
+    seq: u64,
+}
+
+/// Limits the number of simultaneous requests per host and hands free slots out to the
+/// queued requests with the lowest priority value first.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub(crate) struct FetchScheduler {
+    max_in_flight: usize,
+    #[derivative(Debug = "ignore")]
+    state: Mutex<State>,
+}
+
+/// Holds a request slot of a host until dropped.
+pub(crate) struct Permit {
+    scheduler: Option<Arc<FetchScheduler>>,
+    host: String,
+}
+
+impl FetchScheduler {
+    pub fn new(max_in_flight: usize) -> Self {
+        Self {
+            max_in_flight: max_in_flight.max(1),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub async fn acquire(self: &Arc<Self>, host: &str, id: &TileId, priority: u32) -> Permit {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            state.seq += 1;
+            let seq = state.seq;
+            let queue = state.hosts.entry(host.to_owned()).or_default();
+            if queue.in_flight < self.max_in_flight && queue.waiters.is_empty() {
+                queue.in_flight += 1;
+                return Permit {
+                    scheduler: Some(self.clone()),
+                    host: host.to_owned(),
+                };
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            queue.waiters.push(Waiter {
+                id: id.clone(),
+                priority,
+                seq,
+                sender,
+            });
+            receiver
+        };
+
+        // The sender lives in the queue until a permit is handed over
+        receiver
+            .await
+            .expect("Fetch scheduler dropped a queued request")
+    }
+
+    #[cfg(test)]
+    pub fn queued(&self, host: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state.hosts.get(host).map_or(0, |q| q.waiters.len())
+    }
+
+    /// Reorders queued requests, tiles missing in `priorities` go to the back of the queue.
+    pub fn reprioritize(&self, priorities: &HashMap<TileId, u32>) {
+        let mut state = self.state.lock().unwrap();
+        for queue in state.hosts.values_mut() {
+            let waiters = std::mem::take(&mut queue.waiters);
+            queue.waiters = waiters
+                .into_iter()
+                .map(|mut waiter| {
+                    waiter.priority = priorities.get(&waiter.id).copied().unwrap_or(u32::MAX);
+                    waiter
+                })
+                .collect();
+        }
+    }
+
+    fn release(self: &Arc<Self>, host: &str) {
+        loop {
+            let waiter = {
+                let mut state = self.state.lock().unwrap();
+                let queue = match state.hosts.get_mut(host) {
+                    Some(queue) => queue,
+                    None => return,
+                };
+                match queue.waiters.pop() {
+                    Some(waiter) => waiter,
+                    None => {
+                        queue.in_flight -= 1;
+                        if queue.in_flight == 0 {
+                            state.hosts.remove(host);
+                        }
+                        return;
+                    }
+                }
+            };
+
+            // Handed over outside the lock, the slot stays taken meanwhile
+            let permit = Permit {
+                scheduler: Some(self.clone()),
+                host: host.to_owned(),
+            };
+            match waiter.sender.send(permit) {
+                Ok(()) => return,
+                // The request was dropped while queued, disarm the permit and try the next one
+                Err(mut permit) => permit.scheduler = None,
+            }
+        }
+    }
+}
+
+impl Default for FetchScheduler {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_IN_FLIGHT)
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.release(&self.host);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn tile(x: u32) -> TileId {
+        TileId::new(x, 0, 3)
+    }
+
+    #[tokio::test]
+    async fn it_works() {
+        let scheduler = Arc::new(FetchScheduler::new(2));
+        let first = scheduler.acquire("a.example.com", &tile(0), 0).await;
+        let _second = scheduler.acquire("a.example.com", &tile(0), 0).await;
+        // Other hosts have their own limit
+        let _other = scheduler.acquire("b.example.com", &tile(0), 0).await;
+
+        let low = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.acquire("a.example.com", &tile(10), 10).await }
+        });
+        let high = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.acquire("a.example.com", &tile(1), 1).await }
+        });
+        while scheduler.queued("a.example.com") < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        drop(first);
+        let high = high.await.unwrap();
+        assert_eq!(scheduler.queued("a.example.com"), 1);
+        assert!(low.now_or_never().is_none());
+        drop(high);
+    }
+
+    #[tokio::test]
+    async fn cancelled_requests_are_skipped() {
+        let scheduler = Arc::new(FetchScheduler::new(1));
+        let first = scheduler.acquire("a.example.com", &tile(0), 0).await;
+
+        let cancelled = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.acquire("a.example.com", &tile(0), 0).await }
+        });
+        let waiting = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.acquire("a.example.com", &tile(5), 5).await }
+        });
+        while scheduler.queued("a.example.com") < 2 {
+            tokio::task::yield_now().await;
+        }
+        cancelled.abort();
+        let _ = cancelled.await;
+
+        drop(first);
+        let permit = waiting.await.unwrap();
+        drop(permit);
+        assert_eq!(scheduler.queued("a.example.com"), 0);
+        let _again = scheduler.acquire("a.example.com", &tile(0), 0).await;
+    }
+
+    #[tokio::test]
+    async fn reprioritize() {
+        let scheduler = Arc::new(FetchScheduler::new(1));
+        let first = scheduler.acquire("a.example.com", &tile(0), 0).await;
+
+        let mut waiting = Vec::new();
+        for x in 1..=2 {
+            waiting.push(tokio::spawn({
+                let scheduler = scheduler.clone();
+                async move { scheduler.acquire("a.example.com", &tile(x), x).await }
+            }));
+        }
+        while scheduler.queued("a.example.com") < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        // Tile 1 left the viewport, tile 2 is now the most important one
+        let priorities = vec![(tile(2), 0)].into_iter().collect();
+        scheduler.reprioritize(&priorities);
+        drop(first);
+
+        let second = waiting.pop().unwrap().await.unwrap();
+        assert!(waiting.pop().unwrap().now_or_never().is_none());
+        drop(second);
+    }
+}
@@ -0,0 +1,198 @@
+use super::TileSource;
+use crate::{
+    disk_cache::DiskCache,
+    network_manager::{HttpConfig, NetworkManager, RetryPolicy},
+    tile_id::TileId,
+};
+use bytes::Bytes;
+use eyre::{eyre, Result};
+use futures::future::{BoxFuture, FutureExt};
+use std::{collections::HashMap, path::Path};
+
+#[derive(Debug)]
+pub struct HttpTileSource {
+    nm: NetworkManager,
+    url_template: String,
+    /// Host part of the template, requests to all subdomains share its request limit.
+    host: String,
+    subdomains: Vec<String>,
+    min_zoom: u32,
+    max_zoom: u32,
+    attribution: Option<String>,
+}
+
+impl HttpTileSource {
+    pub fn new(url_template: &str) -> Result<Self> {
+        for placeholder in &["{z}", "{x}", "{y}"] {
+            if !url_template.contains(placeholder) {
+                return Err(eyre!(
+                    "URL template {} has no {} placeholder",
+                    url_template,
+                    placeholder
+                ));
+            }
+        }
+
+        Ok(Self {
+            nm: NetworkManager::new()?,
+            url_template: url_template.to_owned(),
+            host: HttpTileSource::template_host(url_template),
+            subdomains: Vec::new(),
+            min_zoom: 0,
+            max_zoom: 19,
+            attribution: None,
+        })
+    }
+
+    /// OpenStreetMap asks to use a single host without subdomains and at most two
+    /// simultaneous requests.
+    pub fn openstreetmap() -> Result<Self> {
+        Ok(Self::new("https://tile.openstreetmap.org/{z}/{x}/{y}.png")?
+            .with_attribution("© OpenStreetMap contributors")
+            .with_max_in_flight(2))
+    }
+
+    pub fn with_subdomains(mut self, subdomains: &[&str]) -> Self {
+        self.subdomains = subdomains.iter().map(|s| (*s).to_owned()).collect();
+        self
+    }
+
+    pub fn with_zoom_range(mut self, min_zoom: u32, max_zoom: u32) -> Self {
+        self.min_zoom = min_zoom;
+        self.max_zoom = max_zoom;
+        self
+    }
+
+    pub fn with_attribution(mut self, attribution: &str) -> Self {
+        self.attribution = Some(attribution.to_owned());
+        self
+    }
+
+    /// Keeps downloaded tiles under `path`, evicting the least recently used ones once the
+    /// cache grows over `max_size` bytes. Use a separate directory for every source.
+    pub fn with_disk_cache<P: AsRef<Path>>(mut self, path: P, max_size: u64) -> Result<Self> {
+        self.nm.set_disk_cache(DiskCache::open(path, max_size)?);
+        Ok(self)
+    }
+
+    /// Fails if a header or the proxy URL is invalid.
+    pub fn with_http_config(mut self, config: HttpConfig) -> Result<Self> {
+        self.nm.set_config(config)?;
+        Ok(self)
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.nm.set_retry_policy(retry_policy);
+        self
+    }
+
+    /// Limits simultaneous requests to the tile server, the `{s}` subdomains count as one
+    /// host. Further requests wait in a priority queue.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.nm.set_max_in_flight(max_in_flight);
+        self
+    }
+
+    fn template_host(url_template: &str) -> String {
+        let rest = match url_template.find("://") {
+            Some(i) => &url_template[i + 3..],
+            None => url_template,
+        };
+        rest.split('/').next().unwrap_or_default().to_owned()
+    }
+
+    pub(crate) fn tile_url(&self, id: &TileId) -> Result<String> {
+        let mut url = self
+            .url_template
+            .replace("{z}", &id.z().to_string())
+            .replace("{x}", &id.x().to_string())
+            .replace("{y}", &id.y().to_string());
+
+        if url.contains("{s}") {
+            if self.subdomains.is_empty() {
+                return Err(eyre!(
+                    "URL template {} uses {{s}} but no subdomains are set",
+                    self.url_template
+                ));
+            }
+            // Spread neighbouring tiles over the subdomains deterministically so the
+            // same tile always maps to the same host and stays cacheable.
+            let index = (id.x() + id.y()) as usize % self.subdomains.len();
+            url = url.replace("{s}", &self.subdomains[index]);
+        }
+
+        Ok(url)
+    }
+}
+
+impl TileSource for HttpTileSource {
+    fn load_tile<'a>(&'a self, id: &'a TileId) -> BoxFuture<'a, Result<Bytes>> {
+        self.load_tile_with_priority(id, 0)
+    }
+
+    fn load_tile_with_priority<'a>(
+        &'a self,
+        id: &'a TileId,
+        priority: u32,
+    ) -> BoxFuture<'a, Result<Bytes>> {
+        async move {
+            let z = u32::from(id.z());
+            if z < self.min_zoom || z > self.max_zoom {
+                return Err(eyre!(
+                    "Zoom {} is outside of the source range {}..={}",
+                    id.z(),
+                    self.min_zoom,
+                    self.max_zoom
+                ));
+            }
+
+            let url = self.tile_url(id)?;
+            self.nm.load_tile(id, &url, &self.host, priority).await
+        }
+        .boxed()
+    }
+
+    fn reprioritize(&self, priorities: &HashMap<TileId, u32>) {
+        self.nm.reprioritize(priorities);
+    }
+
+    fn min_zoom(&self) -> u32 {
+        self.min_zoom
+    }
+
+    fn max_zoom(&self) -> u32 {
+        self.max_zoom
+    }
+
+    fn attribution(&self) -> Option<&str> {
+        self.attribution.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let source = HttpTileSource::new("https://tiles.example.com/{z}/{x}/{y}.png").unwrap();
+        assert_eq!(
+            source.tile_url(&TileId::new(3, 5, 4)).unwrap(),
+            "https://tiles.example.com/4/3/5.png"
+        );
+
+        let source = HttpTileSource::new("http://{s}.tile.example.com/{z}/{x}/{y}.png")
+            .unwrap()
+            .with_subdomains(&["a", "b", "c"]);
+        assert_eq!(
+            source.tile_url(&TileId::new(1, 0, 2)).unwrap(),
+            "http://b.tile.example.com/2/1/0.png"
+        );
+        assert_eq!(source.host, "{s}.tile.example.com");
+
+        let source = HttpTileSource::new("http://{s}.tile.example.com/{z}/{x}/{y}.png").unwrap();
+        assert!(source.tile_url(&TileId::new(1, 0, 2)).is_err());
+
+        assert!(HttpTileSource::new("http://tile.example.com/{z}/{x}.png").is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use super::TileSource;
+use crate::{error::TileError, tile_id::TileId};
+use bytes::Bytes;
+use eyre::{eyre, Result};
+use futures::future::{BoxFuture, FutureExt};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MbTilesMetadata {
+    pub name: Option<String>,
+    pub format: String,
+    /// West, south, east, north in degrees.
+    pub bounds: Option<(f64, f64, f64, f64)>,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    pub attribution: Option<String>,
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct MbTilesSource {
+    #[derivative(Debug = "ignore")]
+    conn: Arc<Mutex<Connection>>,
+    metadata: MbTilesMetadata,
+}
+
+impl MbTilesSource {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        Self::from_connection(conn)
+    }
+
+    pub(crate) fn from_connection(conn: Connection) -> Result<Self> {
+        let metadata = Self::read_metadata(&conn)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            metadata,
+        })
+    }
+
+    pub fn metadata(&self) -> &MbTilesMetadata {
+        &self.metadata
+    }
+
+    fn read_metadata(conn: &Connection) -> Result<MbTilesMetadata> {
+        let mut values = HashMap::new();
+        let mut stmt = conn.prepare("SELECT name, value FROM metadata")?;
+        let rows = stmt.query_map(params![], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (name, value) = row?;
+            values.insert(name, value);
+        }
+
+        let format = values
+            .remove("format")
+            .unwrap_or_else(|| "png".to_owned())
+            .to_lowercase();
+        if format == "pbf" {
+            return Err(eyre!("Vector MBTiles are not supported"));
+        }
+
+        let bounds = match values.remove("bounds") {
+            Some(bounds) => Some(Self::parse_bounds(&bounds)?),
+            None => None,
+        };
+
+        // minzoom and maxzoom are optional in the spec, fall back to what is in the tiles table
+        let (tiles_min_zoom, tiles_max_zoom): (Option<u32>, Option<u32>) = conn.query_row(
+            "SELECT MIN(zoom_level), MAX(zoom_level) FROM tiles",
+            params![],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let min_zoom = match values.remove("minzoom") {
+            Some(zoom) => zoom.trim().parse()?,
+            None => tiles_min_zoom.unwrap_or(0),
+        };
+        let max_zoom = match values.remove("maxzoom") {
+            Some(zoom) => zoom.trim().parse()?,
+            None => tiles_max_zoom.unwrap_or(min_zoom),
+        };
+
+        Ok(MbTilesMetadata {
+            name: values.remove("name"),
+            format,
+            bounds,
+            min_zoom,
+            max_zoom,
+            attribution: values.remove("attribution"),
+        })
+    }
+
+    /// Whether `id` overlaps the bounds of the metadata, the archive has no tiles elsewhere.
+    fn in_bounds(&self, id: &TileId) -> bool {
+        let (west, south, east, north) = match self.metadata.bounds {
+            Some(bounds) => bounds,
+            None => return true,
+        };
+        let tile = id.bounds();
+        let lng_overlaps = if west <= east {
+            tile.max().x > west && tile.min().x < east
+        } else {
+            // The bounds cross the antimeridian
+            tile.max().x > west || tile.min().x < east
+        };
+        lng_overlaps && tile.max().y > south && tile.min().y < north
+    }
+
+    fn parse_bounds(bounds: &str) -> Result<(f64, f64, f64, f64)> {
+        let values = bounds
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        match values[..] {
+            [west, south, east, north] => Ok((west, south, east, north)),
+            _ => Err(eyre!("Invalid MBTiles bounds {}", bounds)),
+        }
+    }
+}
+
+impl TileSource for MbTilesSource {
+    fn load_tile<'a>(&'a self, id: &'a TileId) -> BoxFuture<'a, Result<Bytes>> {
+        let conn = self.conn.clone();
+        let (z, x) = (id.z(), id.x());
+        async move {
+            // MBTiles rows follow the TMS scheme with the origin in the bottom left corner
+            let row = id
+                .tms_y()
+                .ok_or_else(|| eyre!("Tile {}/{}/{} is out of range", z, x, id.y()))?;
+            if !self.in_bounds(id) {
+                return Err(TileError::NotFound.into());
+            }
+            let data = tokio::task::spawn_blocking(move || {
+                let conn = conn
+                    .lock()
+                    .map_err(|_| eyre!("MBTiles connection is poisoned"))?;
+                let data: Option<Vec<u8>> = conn
+                    .query_row(
+                        "SELECT tile_data FROM tiles \
+                         WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                        params![z, x, row],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                Ok::<_, eyre::Report>(data)
+            })
+            .await??;
+
+            Ok(data.map(Bytes::from).ok_or(TileError::NotFound)?)
+        }
+        .boxed()
+    }
+
+    fn min_zoom(&self) -> u32 {
+        self.metadata.min_zoom
+    }
+
+    fn max_zoom(&self) -> u32 {
+        self.metadata.max_zoom
+    }
+
+    fn attribution(&self) -> Option<&str> {
+        self.metadata.attribution.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_archive(metadata: &[(&str, &str)]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE metadata (name TEXT, value TEXT);
+             CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+             INSERT INTO tiles VALUES (1, 0, 1, x'0102');
+             INSERT INTO tiles VALUES (2, 1, 0, x'0304');",
+        )
+        .unwrap();
+        for (name, value) in metadata {
+            conn.execute("INSERT INTO metadata VALUES (?1, ?2)", params![name, value])
+                .unwrap();
+        }
+        conn
+    }
+
+    #[tokio::test]
+    async fn it_works() {
+        let conn = create_archive(&[
+            ("name", "test"),
+            ("format", "jpg"),
+            ("bounds", "-180.0,-85.0511,180.0,85.0511"),
+            ("minzoom", "0"),
+            ("maxzoom", "14"),
+        ]);
+        let source = MbTilesSource::from_connection(conn).unwrap();
+        assert_eq!(
+            source.metadata(),
+            &MbTilesMetadata {
+                name: Some("test".to_owned()),
+                format: "jpg".to_owned(),
+                bounds: Some((-180.0, -85.0511, 180.0, 85.0511)),
+                min_zoom: 0,
+                max_zoom: 14,
+                attribution: None,
+            }
+        );
+
+        let tile = source.load_tile(&TileId::new(0, 0, 1)).await.unwrap();
+        assert_eq!(&tile[..], &[1, 2]);
+        let tile = source.load_tile(&TileId::new(1, 3, 2)).await.unwrap();
+        assert_eq!(&tile[..], &[3, 4]);
+        assert!(source.load_tile(&TileId::new(0, 1, 1)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn bounds() {
+        // Only the north east quarter of the world, the tiles are in the west
+        let conn = create_archive(&[("bounds", "0,0,180,85")]);
+        let source = MbTilesSource::from_connection(conn).unwrap();
+        for id in &[TileId::new(0, 0, 1), TileId::new(1, 3, 2)] {
+            let e = source.load_tile(id).await.unwrap_err();
+            assert!(matches!(e.downcast_ref(), Some(TileError::NotFound)));
+        }
+        assert!(source.in_bounds(&TileId::new(1, 0, 1)));
+        assert!(source.in_bounds(&TileId::new(0, 0, 0)));
+
+        let conn = create_archive(&[("bounds", "170,-10,-170,10")]);
+        let source = MbTilesSource::from_connection(conn).unwrap();
+        assert!(source.in_bounds(&TileId::new(0, 1, 2)));
+        assert!(source.in_bounds(&TileId::new(3, 2, 2)));
+        assert!(!source.in_bounds(&TileId::new(1, 1, 2)));
+    }
+
+    #[test]
+    fn zoom_range_falls_back_to_tiles() {
+        let source = MbTilesSource::from_connection(create_archive(&[])).unwrap();
+        assert_eq!(source.metadata().format, "png");
+        assert_eq!(source.metadata().min_zoom, 1);
+        assert_eq!(source.metadata().max_zoom, 2);
+
+        assert!(MbTilesSource::from_connection(create_archive(&[("format", "pbf")])).is_err());
+    }
+}
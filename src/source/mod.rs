@@ -0,0 +1,45 @@
+mod http;
+mod mbtiles;
+mod pmtiles;
+
+pub use http::HttpTileSource;
+pub use mbtiles::{MbTilesMetadata, MbTilesSource};
+pub use pmtiles::{
+    Compression, FileRangeReader, PmTilesHeader, PmTilesSource, RangeReader, TileType,
+};
+
+use crate::tile_id::TileId;
+use bytes::Bytes;
+use eyre::Result;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+
+pub trait TileSource: Send + Sync {
+    fn load_tile<'a>(&'a self, id: &'a TileId) -> BoxFuture<'a, Result<Bytes>>;
+
+    /// Like `load_tile`, but lets sources with limited throughput serve the requests with
+    /// a lower `priority` value first.
+    fn load_tile_with_priority<'a>(
+        &'a self,
+        id: &'a TileId,
+        _priority: u32,
+    ) -> BoxFuture<'a, Result<Bytes>> {
+        self.load_tile(id)
+    }
+
+    /// Called when the viewport changes with the new priority of every required tile.
+    /// Requests for tiles missing in `priorities` are no longer needed.
+    fn reprioritize(&self, _priorities: &HashMap<TileId, u32>) {}
+
+    fn min_zoom(&self) -> u32 {
+        0
+    }
+
+    fn max_zoom(&self) -> u32 {
+        19
+    }
+
+    fn attribution(&self) -> Option<&str> {
+        None
+    }
+}
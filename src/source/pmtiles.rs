@@ -0,0 +1,657 @@
+use super::TileSource;
+use crate::{error::TileError, tile_id::TileId};
+use bytes::{Buf, Bytes};
+use eyre::{eyre, Result};
+use flate2::read::GzDecoder;
+use futures::future::{BoxFuture, FutureExt};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+const HEADER_LENGTH: u64 = 127;
+const MAX_DIRECTORY_DEPTH: usize = 4;
+// Leaf directories hold up to a few thousand entries each
+const MAX_LEAF_DIRECTORIES: usize = 64;
+
+type Directory = Arc<Vec<Entry>>;
+
+pub trait RangeReader: Send + Sync {
+    fn read_range(&self, offset: u64, length: u64) -> BoxFuture<'_, Result<Bytes>>;
+}
+
+impl RangeReader for Bytes {
+    fn read_range(&self, offset: u64, length: u64) -> BoxFuture<'_, Result<Bytes>> {
+        let result = match offset.checked_add(length) {
+            Some(end) if end <= self.len() as u64 => Ok(self.slice(offset as usize..end as usize)),
+            _ => Err(eyre!(
+                "Range of {} bytes at {} is out of bounds",
+                length,
+                offset
+            )),
+        };
+        futures::future::ready(result).boxed()
+    }
+}
+
+#[derive(Debug)]
+pub struct FileRangeReader {
+    file: Arc<Mutex<File>>,
+}
+
+impl FileRangeReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+}
+
+impl RangeReader for FileRangeReader {
+    fn read_range(&self, offset: u64, length: u64) -> BoxFuture<'_, Result<Bytes>> {
+        let file = self.file.clone();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let mut file = file.lock().map_err(|_| eyre!("PMTiles file is poisoned"))?;
+                let mut buf = vec![0; length as usize];
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut buf)?;
+                Ok(Bytes::from(buf))
+            })
+            .await?
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Unknown,
+    None,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Compression {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Compression::None,
+            2 => Compression::Gzip,
+            3 => Compression::Brotli,
+            4 => Compression::Zstd,
+            _ => Compression::Unknown,
+        }
+    }
+
+    fn decompress(self, data: Bytes) -> Result<Bytes> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Gzip => {
+                let mut out = Vec::new();
+                GzDecoder::new(&data[..]).read_to_end(&mut out)?;
+                Ok(Bytes::from(out))
+            }
+            _ => Err(eyre!("{:?} compression is not supported", self)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileType {
+    Unknown,
+    Mvt,
+    Png,
+    Jpeg,
+    Webp,
+    Avif,
+}
+
+impl TileType {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => TileType::Mvt,
+            2 => TileType::Png,
+            3 => TileType::Jpeg,
+            4 => TileType::Webp,
+            5 => TileType::Avif,
+            _ => TileType::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PmTilesHeader {
+    pub root_directory_offset: u64,
+    pub root_directory_length: u64,
+    pub metadata_offset: u64,
+    pub metadata_length: u64,
+    pub leaf_directories_offset: u64,
+    pub leaf_directories_length: u64,
+    pub tile_data_offset: u64,
+    pub tile_data_length: u64,
+    pub internal_compression: Compression,
+    pub tile_compression: Compression,
+    pub tile_type: TileType,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    /// West, south, east, north in degrees.
+    pub bounds: (f64, f64, f64, f64),
+    pub center_zoom: u8,
+    /// Longitude and latitude in degrees.
+    pub center: (f64, f64),
+}
+
+impl PmTilesHeader {
+    fn parse(mut buf: &[u8]) -> Result<Self> {
+        if buf.len() < HEADER_LENGTH as usize || &buf[..7] != b"PMTiles" {
+            return Err(eyre!("Not a PMTiles archive"));
+        }
+        buf.advance(7);
+        let version = buf.get_u8();
+        if version != 3 {
+            return Err(eyre!("Unsupported PMTiles version {}", version));
+        }
+
+        let root_directory_offset = buf.get_u64_le();
+        let root_directory_length = buf.get_u64_le();
+        let metadata_offset = buf.get_u64_le();
+        let metadata_length = buf.get_u64_le();
+        let leaf_directories_offset = buf.get_u64_le();
+        let leaf_directories_length = buf.get_u64_le();
+        let tile_data_offset = buf.get_u64_le();
+        let tile_data_length = buf.get_u64_le();
+        // Addressed tiles, tile entries and tile contents counts plus the clustered flag
+        buf.advance(3 * 8 + 1);
+        let internal_compression = Compression::from_u8(buf.get_u8());
+        let tile_compression = Compression::from_u8(buf.get_u8());
+        let tile_type = TileType::from_u8(buf.get_u8());
+        let min_zoom = buf.get_u8();
+        let max_zoom = buf.get_u8();
+        let mut coordinate = || buf.get_i32_le() as f64 / 10_000_000.0;
+        let bounds = (coordinate(), coordinate(), coordinate(), coordinate());
+        let center_zoom = buf.get_u8();
+        let center = (
+            buf.get_i32_le() as f64 / 10_000_000.0,
+            buf.get_i32_le() as f64 / 10_000_000.0,
+        );
+
+        Ok(Self {
+            root_directory_offset,
+            root_directory_length,
+            metadata_offset,
+            metadata_length,
+            leaf_directories_offset,
+            leaf_directories_length,
+            tile_data_offset,
+            tile_data_length,
+            internal_compression,
+            tile_compression,
+            tile_type,
+            min_zoom,
+            max_zoom,
+            bounds,
+            center_zoom,
+            center,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u64,
+    run_length: u64,
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        if !buf.has_remaining() || shift >= 64 {
+            return Err(eyre!("Malformed varint in PMTiles directory"));
+        }
+        let byte = buf.get_u8();
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+fn parse_directory(mut buf: &[u8]) -> Result<Vec<Entry>> {
+    let buf = &mut buf;
+    let count = read_varint(buf)?;
+    // Each entry takes at least one byte per column, so the count can't be trusted further
+    if count > buf.remaining() as u64 / 4 {
+        return Err(eyre!(
+            "PMTiles directory of {} entries is longer than its data",
+            count
+        ));
+    }
+    let count = count as usize;
+    let mut entries = Vec::with_capacity(count);
+
+    let mut tile_id = 0u64;
+    for _ in 0..count {
+        tile_id = tile_id
+            .checked_add(read_varint(buf)?)
+            .ok_or_else(|| eyre!("Tile id overflow in PMTiles directory"))?;
+        entries.push(Entry {
+            tile_id,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        });
+    }
+    for entry in entries.iter_mut() {
+        entry.run_length = read_varint(buf)?;
+    }
+    for entry in entries.iter_mut() {
+        entry.length = read_varint(buf)?;
+    }
+    for i in 0..count {
+        let value = read_varint(buf)?;
+        // Zero means the data directly follows the previous entry
+        entries[i].offset = if value == 0 && i > 0 {
+            entries[i - 1]
+                .offset
+                .checked_add(entries[i - 1].length)
+                .ok_or_else(|| eyre!("Offset overflow in PMTiles directory"))?
+        } else {
+            value.saturating_sub(1)
+        };
+    }
+
+    Ok(entries)
+}
+
+fn find_entry(entries: &[Entry], tile_id: u64) -> Option<&Entry> {
+    let index = match entries.binary_search_by_key(&tile_id, |e| e.tile_id) {
+        Ok(index) => index,
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+    let entry = &entries[index];
+    // Leaf directory pointers have zero run length and cover everything up to the next entry
+    if entry.run_length == 0 || tile_id < entry.tile_id + entry.run_length {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+fn rotate(n: u32, x: u32, y: u32, rx: u32, ry: u32) -> (u32, u32) {
+    if ry == 0 {
+        if rx != 0 {
+            return (
+                n.wrapping_sub(1).wrapping_sub(y),
+                n.wrapping_sub(1).wrapping_sub(x),
+            );
+        }
+        return (y, x);
+    }
+    (x, y)
+}
+
+pub(crate) fn zxy_to_tile_id(z: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut acc = ((1u64 << (z * 2)) - 1) / 3;
+    let mut s = if z > 0 { 1u32 << (z - 1) } else { 0 };
+    while s > 0 {
+        let rx = s & x;
+        let ry = s & y;
+        acc += u64::from((3 * rx) ^ ry) * u64::from(s);
+        let (nx, ny) = rotate(s, x, y, rx, ry);
+        x = nx;
+        y = ny;
+        s >>= 1;
+    }
+    acc
+}
+
+/// Least recently used leaf directories, by offset and length.
+struct LeafCache {
+    max_entries: usize,
+    entries: HashMap<(u64, u64), (Directory, u64)>,
+    lru: BTreeMap<u64, (u64, u64)>,
+    clock: u64,
+}
+
+impl LeafCache {
+    fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, key: &(u64, u64)) -> Option<Directory> {
+        self.clock += 1;
+        let (leaf, last_used) = self.entries.get_mut(key)?;
+        self.lru.remove(last_used);
+        *last_used = self.clock;
+        self.lru.insert(self.clock, *key);
+        Some(leaf.clone())
+    }
+
+    fn insert(&mut self, key: (u64, u64), leaf: Directory) {
+        self.clock += 1;
+        if let Some((_, last_used)) = self.entries.insert(key, (leaf, self.clock)) {
+            self.lru.remove(&last_used);
+        }
+        self.lru.insert(self.clock, key);
+
+        while self.entries.len() > self.max_entries {
+            let oldest = match self.lru.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(key) = self.lru.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct PmTilesSource {
+    #[derivative(Debug = "ignore")]
+    reader: Box<dyn RangeReader>,
+    header: PmTilesHeader,
+    root: Vec<Entry>,
+    #[derivative(Debug = "ignore")]
+    leaves: Mutex<LeafCache>,
+}
+
+impl PmTilesSource {
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_reader(FileRangeReader::open(path)?).await
+    }
+
+    pub async fn from_reader<R: RangeReader + 'static>(reader: R) -> Result<Self> {
+        let header = PmTilesHeader::parse(&reader.read_range(0, HEADER_LENGTH).await?)?;
+        match header.tile_type {
+            TileType::Png | TileType::Jpeg | TileType::Webp | TileType::Unknown => {}
+            tile_type => return Err(eyre!("{:?} PMTiles are not supported", tile_type)),
+        }
+
+        let data = reader
+            .read_range(header.root_directory_offset, header.root_directory_length)
+            .await?;
+        let root = parse_directory(&header.internal_compression.decompress(data)?)?;
+
+        Ok(Self {
+            reader: Box::new(reader),
+            header,
+            root,
+            leaves: Mutex::new(LeafCache::new(MAX_LEAF_DIRECTORIES)),
+        })
+    }
+
+    pub fn header(&self) -> &PmTilesHeader {
+        &self.header
+    }
+
+    async fn leaf_directory(&self, offset: u64, length: u64) -> Result<Directory> {
+        let key = (offset, length);
+        if let Some(leaf) = self.leaves.lock().unwrap().get(&key) {
+            return Ok(leaf);
+        }
+
+        let data = self
+            .reader
+            .read_range(self.header.leaf_directories_offset + offset, length)
+            .await?;
+        let leaf = Arc::new(parse_directory(
+            &self.header.internal_compression.decompress(data)?,
+        )?);
+        self.leaves.lock().unwrap().insert(key, leaf.clone());
+        Ok(leaf)
+    }
+
+    async fn find_tile(&self, tile_id: u64) -> Result<Option<(u64, u64)>> {
+        let mut found = find_entry(&self.root, tile_id).cloned();
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            let entry = match found {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+            if entry.run_length > 0 {
+                return Ok(Some((entry.offset, entry.length)));
+            }
+            let leaf = self.leaf_directory(entry.offset, entry.length).await?;
+            found = find_entry(&leaf, tile_id).cloned();
+        }
+
+        Err(eyre!("PMTiles directories are nested too deep"))
+    }
+}
+
+impl TileSource for PmTilesSource {
+    fn load_tile<'a>(&'a self, id: &'a TileId) -> BoxFuture<'a, Result<Bytes>> {
+        async move {
+            if !id.is_valid() {
+                return Err(eyre!("Tile {}/{}/{} is out of range", id.z, id.x, id.y));
+            }
+            let (z, x, y) = (u32::from(id.z()), id.x(), id.y());
+
+            let (offset, length) = self
+                .find_tile(zxy_to_tile_id(z, x, y))
+                .await?
+                .ok_or(TileError::NotFound)?;
+            let data = self
+                .reader
+                .read_range(self.header.tile_data_offset + offset, length)
+                .await?;
+            self.header.tile_compression.decompress(data)
+        }
+        .boxed()
+    }
+
+    fn min_zoom(&self) -> u32 {
+        self.header.min_zoom.into()
+    }
+
+    fn max_zoom(&self) -> u32 {
+        self.header.max_zoom.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{BufMut, BytesMut};
+    use flate2::{write::GzEncoder, Compression as GzCompression};
+    use std::io::Write;
+
+    fn write_varint(buf: &mut BytesMut, mut value: u64) {
+        while value >= 0x80 {
+            buf.put_u8((value as u8) | 0x80);
+            value >>= 7;
+        }
+        buf.put_u8(value as u8);
+    }
+
+    fn serialize_directory(entries: &[Entry]) -> Bytes {
+        let mut buf = BytesMut::new();
+        write_varint(&mut buf, entries.len() as u64);
+        let mut last_id = 0;
+        for e in entries {
+            write_varint(&mut buf, e.tile_id - last_id);
+            last_id = e.tile_id;
+        }
+        for e in entries {
+            write_varint(&mut buf, e.run_length);
+        }
+        for e in entries {
+            write_varint(&mut buf, e.length);
+        }
+        for (i, e) in entries.iter().enumerate() {
+            if i > 0 && e.offset == entries[i - 1].offset + entries[i - 1].length {
+                write_varint(&mut buf, 0);
+            } else {
+                write_varint(&mut buf, e.offset + 1);
+            }
+        }
+        buf.freeze()
+    }
+
+    fn gzip(data: &[u8]) -> Bytes {
+        let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+        encoder.write_all(data).unwrap();
+        Bytes::from(encoder.finish().unwrap())
+    }
+
+    fn entry(tile_id: u64, offset: u64, length: u64, run_length: u64) -> Entry {
+        Entry {
+            tile_id,
+            offset,
+            length,
+            run_length,
+        }
+    }
+
+    fn build_archive(root: &[u8], leaves: &[u8], tiles: &[u8]) -> Bytes {
+        let root_offset = HEADER_LENGTH;
+        let leaves_offset = root_offset + root.len() as u64;
+        let tiles_offset = leaves_offset + leaves.len() as u64;
+
+        let mut buf = BytesMut::new();
+        buf.put_slice(b"PMTiles");
+        buf.put_u8(3);
+        for value in &[
+            root_offset,
+            root.len() as u64,
+            leaves_offset,
+            0,
+            leaves_offset,
+            leaves.len() as u64,
+            tiles_offset,
+            tiles.len() as u64,
+            0,
+            0,
+            0,
+        ] {
+            buf.put_u64_le(*value);
+        }
+        buf.put_u8(1); // clustered
+        buf.put_u8(2); // gzip internal compression
+        buf.put_u8(1); // no tile compression
+        buf.put_u8(2); // png
+        buf.put_u8(0);
+        buf.put_u8(3);
+        for value in &[-1_800_000_000, -850_511_287, 1_800_000_000, 850_511_287] {
+            buf.put_i32_le(*value);
+        }
+        buf.put_u8(0);
+        buf.put_i32_le(0);
+        buf.put_i32_le(0);
+        assert_eq!(buf.len() as u64, HEADER_LENGTH);
+
+        buf.put_slice(root);
+        buf.put_slice(leaves);
+        buf.put_slice(tiles);
+        buf.freeze()
+    }
+
+    #[test]
+    fn tile_ids() {
+        assert_eq!(zxy_to_tile_id(0, 0, 0), 0);
+        assert_eq!(zxy_to_tile_id(1, 0, 0), 1);
+        assert_eq!(zxy_to_tile_id(1, 0, 1), 2);
+        assert_eq!(zxy_to_tile_id(1, 1, 1), 3);
+        assert_eq!(zxy_to_tile_id(1, 1, 0), 4);
+        assert_eq!(zxy_to_tile_id(2, 0, 0), 5);
+        assert_eq!(zxy_to_tile_id(12, 3423, 1763), 19_078_479);
+    }
+
+    #[test]
+    fn directory_roundtrip() {
+        let entries = vec![
+            entry(0, 0, 10, 1),
+            entry(1, 10, 20, 4),
+            entry(7, 100, 5, 0),
+            entry(1_000, 100, 5, 1),
+        ];
+        assert_eq!(
+            parse_directory(&serialize_directory(&entries)).unwrap(),
+            entries
+        );
+        assert_eq!(find_entry(&entries, 3), Some(&entries[1]));
+        assert_eq!(find_entry(&entries, 5), None);
+        assert_eq!(find_entry(&entries, 500), Some(&entries[2]));
+
+        // A huge entry count must not be trusted for the allocation
+        let mut buf = BytesMut::new();
+        write_varint(&mut buf, u64::MAX);
+        buf.put_slice(&[0; 16]);
+        assert!(parse_directory(&buf).is_err());
+        let mut buf = BytesMut::new();
+        write_varint(&mut buf, 2);
+        write_varint(&mut buf, u64::MAX);
+        write_varint(&mut buf, 1);
+        buf.put_slice(&[1; 6]);
+        assert!(parse_directory(&buf).is_err());
+    }
+
+    #[test]
+    fn byte_ranges() {
+        let data = Bytes::from_static(b"0123456789");
+        let read = |offset, length| futures::executor::block_on(data.read_range(offset, length));
+        assert_eq!(read(2, 3).unwrap(), Bytes::from_static(b"234"));
+        assert_eq!(read(10, 0).unwrap(), Bytes::new());
+        assert!(read(8, 3).is_err());
+        assert!(read(u64::MAX, 2).is_err());
+    }
+
+    #[test]
+    fn leaf_cache() {
+        let leaf = |tile_id| Arc::new(vec![entry(tile_id, 0, 0, 1)]);
+        let mut cache = LeafCache::new(2);
+        cache.insert((0, 1), leaf(0));
+        cache.insert((1, 1), leaf(1));
+        assert!(cache.get(&(0, 1)).is_some());
+
+        // The least recently used leaf goes first
+        cache.insert((2, 1), leaf(2));
+        assert!(cache.get(&(1, 1)).is_none());
+        assert_eq!(cache.get(&(0, 1)), Some(leaf(0)));
+        assert_eq!(cache.get(&(2, 1)), Some(leaf(2)));
+        assert_eq!((cache.entries.len(), cache.lru.len()), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn it_works() {
+        let tiles = b"zerofour-run";
+        let leaf = gzip(&serialize_directory(&[entry(5, 4, 4, 1)]));
+        let root = gzip(&serialize_directory(&[
+            entry(0, 0, 4, 1),
+            entry(1, 8, 4, 4),
+            entry(5, 0, leaf.len() as u64, 0),
+        ]));
+        let archive = build_archive(&root, &leaf, tiles);
+
+        let source = PmTilesSource::from_reader(archive).await.unwrap();
+        assert_eq!(source.header().max_zoom, 3);
+        assert_eq!(source.header().bounds.0, -180.0);
+        assert_eq!(source.header().tile_type, TileType::Png);
+
+        let tile = source.load_tile(&TileId::new(0, 0, 0)).await.unwrap();
+        assert_eq!(&tile[..], b"zero");
+        // Tiles 1..5 share the same data through a run
+        let tile = source.load_tile(&TileId::new(1, 0, 1)).await.unwrap();
+        assert_eq!(&tile[..], b"-run");
+        let tile = source.load_tile(&TileId::new(0, 0, 2)).await.unwrap();
+        assert_eq!(&tile[..], b"four");
+        assert!(source.load_tile(&TileId::new(1, 0, 2)).await.is_err());
+    }
+}
@@ -1,31 +1,57 @@
 use std::sync::Arc;
 
 use crate::{tile_coordinates::TileCoordinates, tile_id::TileId};
-use bytes::Bytes;
+use image::RgbaImage;
 
-#[derive(Derivative)]
+#[derive(Derivative, Clone)]
 #[derivative(Debug)]
 pub(crate) struct Tile {
     id: TileId,
     #[derivative(Debug = "ignore")]
-    data: Arc<Bytes>,
+    data: Option<Arc<RgbaImage>>,
     coords: TileCoordinates,
+    /// Tile the data belongs to, an ancestor of `id` while `id` itself is not loaded.
+    data_id: TileId,
 }
 
 impl Tile {
-    pub fn new(id: &TileId, data: Arc<Bytes>, coords: &TileCoordinates) -> Tile {
+    pub fn new(id: &TileId, data: Option<Arc<RgbaImage>>, coords: &TileCoordinates) -> Tile {
         Self {
             id: id.clone(),
             data,
             coords: coords.clone(),
+            data_id: id.clone(),
         }
     }
 
+    /// Draws the part of `ancestor` that covers this tile instead of the placeholder.
+    pub fn with_ancestor(mut self, ancestor: &TileId, data: Arc<RgbaImage>) -> Self {
+        let levels = self.id.z() - ancestor.z();
+        let size = 1.0 / (1u32 << levels) as f32;
+        let left = (self.id.x() - (ancestor.x() << levels)) as f32 * size;
+        let top = (self.id.y() - (ancestor.y() << levels)) as f32 * size;
+        self.coords = self.coords.crop(left, top, size);
+        self.data = Some(data);
+        self.data_id = ancestor.clone();
+        self
+    }
+
+    #[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+    pub fn data_id(&self) -> &TileId {
+        &self.data_id
+    }
+
+    #[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+    pub fn id(&self) -> &TileId {
+        &self.id
+    }
+
     pub fn coords(&self) -> &TileCoordinates {
         &self.coords
     }
 
-    pub fn data(&self) -> &[u8] {
-        &self.data[..]
+    /// `None` while the tile is not loaded, it is drawn with the placeholder then.
+    pub fn data(&self) -> Option<&RgbaImage> {
+        self.data.as_deref()
     }
 }
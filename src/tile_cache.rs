@@ -0,0 +1,225 @@
+use crate::tile_id::TileId;
+use image::RgbaImage;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+const DEFAULT_MAX_ENTRIES: usize = 1024;
+// Decoded 256 pixel tiles take 256 KiB each
+const DEFAULT_MAX_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    data: Arc<RgbaImage>,
+    last_used: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct TileCache {
+    max_entries: usize,
+    max_bytes: usize,
+    entries: HashMap<TileId, CacheEntry>,
+    lru: BTreeMap<u64, TileId>,
+    pinned: HashSet<TileId>,
+    clock: u64,
+    bytes: usize,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl TileCache {
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        Self {
+            max_entries,
+            max_bytes,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            pinned: HashSet::new(),
+            clock: 0,
+            bytes: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    pub fn get(&mut self, id: &TileId) -> Option<Arc<RgbaImage>> {
+        self.clock += 1;
+        match self.entries.get_mut(id) {
+            Some(entry) => {
+                self.hits += 1;
+                self.lru.remove(&entry.last_used);
+                entry.last_used = self.clock;
+                self.lru.insert(self.clock, id.clone());
+                Some(entry.data.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Looks a tile up without touching the statistics or the LRU order.
+    pub fn peek(&self, id: &TileId) -> Option<Arc<RgbaImage>> {
+        self.entries.get(id).map(|entry| entry.data.clone())
+    }
+
+    #[cfg(test)]
+    pub fn contains(&self, id: &TileId) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    pub fn insert(&mut self, id: TileId, data: Arc<RgbaImage>) {
+        self.clock += 1;
+        self.bytes += data.as_raw().len();
+        self.lru.insert(self.clock, id.clone());
+        let previous = self.entries.insert(
+            id,
+            CacheEntry {
+                data,
+                last_used: self.clock,
+            },
+        );
+        if let Some(previous) = previous {
+            self.bytes -= previous.data.as_raw().len();
+            self.lru.remove(&previous.last_used);
+        }
+
+        self.evict();
+    }
+
+    /// Replaces the set of tiles that must survive eviction, usually the visible ones.
+    pub fn pin<I: IntoIterator<Item = TileId>>(&mut self, ids: I) {
+        self.pinned = ids.into_iter().collect();
+        self.evict();
+    }
+
+    #[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+    pub fn set_limits(&mut self, max_entries: usize, max_bytes: usize) {
+        self.max_entries = max_entries;
+        self.max_bytes = max_bytes;
+        self.evict();
+    }
+
+    #[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            entries: self.entries.len(),
+            bytes: self.bytes,
+        }
+    }
+
+    fn is_over_limit(&self) -> bool {
+        self.entries.len() > self.max_entries || self.bytes > self.max_bytes
+    }
+
+    fn evict(&mut self) {
+        if !self.is_over_limit() {
+            return;
+        }
+
+        let candidates: Vec<_> = self
+            .lru
+            .iter()
+            .filter(|(_, id)| !self.pinned.contains(id))
+            .map(|(last_used, id)| (*last_used, id.clone()))
+            .collect();
+
+        for (last_used, id) in candidates {
+            if !self.is_over_limit() {
+                break;
+            }
+            self.lru.remove(&last_used);
+            if let Some(entry) = self.entries.remove(&id) {
+                self.bytes -= entry.data.as_raw().len();
+                self.evictions += 1;
+            }
+        }
+    }
+}
+
+impl Default for TileCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ENTRIES, DEFAULT_MAX_BYTES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(x: u32) -> TileId {
+        TileId::new(x, 0, 5)
+    }
+
+    /// Image taking `len` bytes.
+    fn data(len: u32) -> Arc<RgbaImage> {
+        Arc::new(RgbaImage::new(len / 4, 1))
+    }
+
+    #[test]
+    fn it_works() {
+        let mut cache = TileCache::new(2, 1024);
+        cache.insert(tile(0), data(40));
+        cache.insert(tile(1), data(40));
+        assert!(cache.get(&tile(0)).is_some());
+        cache.insert(tile(2), data(40));
+
+        assert!(cache.contains(&tile(0)));
+        assert!(!cache.contains(&tile(1)));
+        assert!(cache.get(&tile(1)).is_none());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 1,
+                evictions: 1,
+                entries: 2,
+                bytes: 80,
+            }
+        );
+    }
+
+    #[test]
+    fn byte_limit() {
+        let mut cache = TileCache::new(10, 100);
+        cache.insert(tile(0), data(40));
+        cache.insert(tile(1), data(40));
+        cache.insert(tile(1), data(20));
+        assert_eq!(cache.stats().bytes, 60);
+        cache.insert(tile(2), data(60));
+        assert!(!cache.contains(&tile(0)));
+        assert_eq!(cache.stats().bytes, 80);
+    }
+
+    #[test]
+    fn pinned_tiles_survive() {
+        let mut cache = TileCache::new(1, 1024);
+        cache.pin(vec![tile(0), tile(1)]);
+        cache.insert(tile(0), data(40));
+        cache.insert(tile(1), data(40));
+        cache.insert(tile(2), data(40));
+        assert!(cache.contains(&tile(0)));
+        assert!(cache.contains(&tile(1)));
+        assert!(!cache.contains(&tile(2)));
+
+        cache.pin(vec![tile(1)]);
+        assert!(!cache.contains(&tile(0)));
+        assert_eq!(cache.stats().entries, 1);
+    }
+}
@@ -1,41 +1,237 @@
-use std::hash::{Hash, Hasher};
+use crate::{projection, view::TILE_SIZE};
+use geo::{Coordinate, Point, Rect};
 
-#[derive(Debug, Clone)]
-pub(crate) struct TileId {
-    pub x: f32,
-    pub y: f32,
-    pub z: f32,
+/// Tile in the XYZ scheme, `x` grows to the east and `y` to the south.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct TileId {
+    pub z: u8,
+    pub x: u32,
+    pub y: u32,
 }
 
 impl TileId {
-    pub fn new(x: f32, y: f32, z: f32) -> TileId {
-        Self { x, y, z }
+    /// Deepest zoom level, tile coordinates of a level `z` are below `2^z`.
+    pub const MAX_ZOOM: u8 = 31;
+
+    pub fn new(x: u32, y: u32, z: u8) -> TileId {
+        Self { z, x, y }
+    }
+
+    /// `None` unless the tile exists, see `is_valid`.
+    pub fn checked(x: u32, y: u32, z: u8) -> Option<TileId> {
+        let id = TileId::new(x, y, z);
+        if id.is_valid() {
+            Some(id)
+        } else {
+            None
+        }
     }
 
     pub fn x(&self) -> u32 {
-        self.x as u32
+        self.x
     }
 
     pub fn y(&self) -> u32 {
-        self.y as u32
+        self.y
     }
 
-    pub fn z(&self) -> u32 {
-        self.z as u32
+    pub fn z(&self) -> u8 {
+        self.z
     }
-}
 
-impl Hash for TileId {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        let id = format!("{}/{}/{}", self.z(), self.x(), self.y());
-        id.hash(state);
+    pub fn is_valid(&self) -> bool {
+        self.z <= TileId::MAX_ZOOM && self.x < (1 << self.z) && self.y < (1 << self.z)
+    }
+
+    /// The tile one zoom level up that covers this one.
+    pub fn parent(&self) -> Option<TileId> {
+        if self.z == 0 {
+            return None;
+        }
+        Some(TileId::new(self.x / 2, self.y / 2, self.z - 1))
+    }
+
+    /// The four tiles one zoom level down that cover this one, `None` at `MAX_ZOOM` and
+    /// for invalid tiles.
+    pub fn children(&self) -> Option<[TileId; 4]> {
+        if self.z >= TileId::MAX_ZOOM || !self.is_valid() {
+            return None;
+        }
+        let (x, y, z) = (self.x.checked_mul(2)?, self.y.checked_mul(2)?, self.z + 1);
+        Some([
+            TileId::new(x, y, z),
+            TileId::new(x + 1, y, z),
+            TileId::new(x, y + 1, z),
+            TileId::new(x + 1, y + 1, z),
+        ])
+    }
+
+    /// The up to eight tiles of the same level around this one, row by row.
+    pub fn neighbors(&self) -> Vec<TileId> {
+        let mut neighbors = Vec::with_capacity(8);
+        for dy in -1i64..=1 {
+            for dx in -1i64..=1 {
+                let x = self.x as i64 + dx;
+                let y = self.y as i64 + dy;
+                if (dx, dy) == (0, 0) || x < 0 || y < 0 {
+                    continue;
+                }
+                if let Some(id) = TileId::checked(x as u32, y as u32, self.z) {
+                    neighbors.push(id);
+                }
+            }
+        }
+        neighbors
+    }
+
+    /// Bing Maps quadkey, one digit per zoom level. `None` unless the tile is valid.
+    pub fn quadkey(&self) -> Option<String> {
+        if !self.is_valid() {
+            return None;
+        }
+        (0..u32::from(self.z))
+            .rev()
+            .map(|level| {
+                let mask = 1u32.checked_shl(level)?;
+                let digit = (self.x & mask != 0) as u8 + 2 * (self.y & mask != 0) as u8;
+                Some((b'0' + digit) as char)
+            })
+            .collect()
+    }
+
+    pub fn from_quadkey(quadkey: &str) -> Option<TileId> {
+        if quadkey.len() > TileId::MAX_ZOOM as usize {
+            return None;
+        }
+        let (mut x, mut y) = (0, 0);
+        for digit in quadkey.chars() {
+            let digit = digit.to_digit(4)?;
+            x = x << 1 | (digit & 1);
+            y = y << 1 | (digit >> 1);
+        }
+        Some(TileId::new(x, y, quadkey.len() as u8))
+    }
+
+    /// Row in the TMS scheme, where `y` grows to the north. `None` unless the tile is valid.
+    pub fn tms_y(&self) -> Option<u32> {
+        if !self.is_valid() {
+            return None;
+        }
+        Some((1u32 << self.z) - 1 - self.y)
+    }
+
+    pub fn from_tms(x: u32, tms_y: u32, z: u8) -> Option<TileId> {
+        let y = 1u32
+            .checked_shl(u32::from(z))?
+            .checked_sub(1)?
+            .checked_sub(tms_y)?;
+        TileId::checked(x, y, z)
     }
-}
 
-impl PartialEq for TileId {
-    fn eq(&self, other: &TileId) -> bool {
-        self.x() == other.x() && self.y() == other.y() && self.z() == other.z()
+    /// Area of the tile in longitude and latitude, the minimum is the south west corner.
+    pub fn bounds(&self) -> Rect<f64> {
+        let zoom = f64::from(self.z);
+        let north_west = projection::world_pixel_to_lnglat(
+            &Point::new(self.x as f64 * TILE_SIZE, self.y as f64 * TILE_SIZE),
+            zoom,
+        );
+        let south_east = projection::world_pixel_to_lnglat(
+            // In f64, the tile past u32::MAX of an invalid id does not overflow
+            &Point::new(
+                (f64::from(self.x) + 1.0) * TILE_SIZE,
+                (f64::from(self.y) + 1.0) * TILE_SIZE,
+            ),
+            zoom,
+        );
+        Rect::new(
+            Coordinate {
+                x: north_west.lng(),
+                y: south_east.lat(),
+            },
+            Coordinate {
+                x: south_east.lng(),
+                y: north_west.lat(),
+            },
+        )
     }
 }
 
-impl Eq for TileId {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let id = TileId::new(3, 5, 4);
+        assert!(id.is_valid());
+        assert!(!TileId::new(16, 0, 4).is_valid());
+        assert!(TileId::checked(0, 0, 32).is_none());
+        assert_eq!(id.parent(), Some(TileId::new(1, 2, 3)));
+        assert_eq!(TileId::new(0, 0, 0).parent(), None);
+        assert!(id
+            .children()
+            .unwrap()
+            .iter()
+            .all(|child| child.parent().as_ref() == Some(&id)));
+        assert_eq!(id.tms_y(), Some(10));
+        assert_eq!(TileId::from_tms(3, 10, 4), Some(id));
+        assert_eq!(TileId::new(0, 16, 4).tms_y(), None);
+        assert_eq!(TileId::from_tms(0, 16, 4), None);
+        assert_eq!(TileId::from_tms(16, 0, 4), None);
+        assert_eq!(TileId::from_tms(0, 0, 32), None);
+    }
+
+    #[test]
+    fn neighbors() {
+        assert_eq!(TileId::new(1, 1, 2).neighbors().len(), 8);
+        assert_eq!(
+            TileId::new(0, 0, 1).neighbors(),
+            vec![
+                TileId::new(1, 0, 1),
+                TileId::new(0, 1, 1),
+                TileId::new(1, 1, 1)
+            ]
+        );
+        assert!(TileId::new(0, 0, 0).neighbors().is_empty());
+    }
+
+    #[test]
+    fn quadkey() {
+        let id = TileId::new(3, 5, 3);
+        assert_eq!(id.quadkey().as_deref(), Some("213"));
+        assert_eq!(TileId::from_quadkey("213"), Some(id));
+        assert_eq!(TileId::from_quadkey(""), Some(TileId::new(0, 0, 0)));
+        assert_eq!(TileId::from_quadkey("14"), None);
+        assert_eq!(TileId::new(8, 0, 3).quadkey(), None);
+    }
+
+    #[test]
+    fn max_zoom() {
+        let max = (1u32 << TileId::MAX_ZOOM) - 1;
+        let id = TileId::new(max, max, TileId::MAX_ZOOM);
+        assert!(id.is_valid());
+        assert_eq!(id.children(), None);
+        assert_eq!(TileId::new(u32::MAX, 0, 30).children(), None);
+        let deepest = TileId::new(max / 2, max / 2, 30).children().unwrap();
+        assert!(deepest.iter().all(TileId::is_valid));
+
+        let quadkey = id.quadkey().unwrap();
+        assert_eq!(quadkey, "3".repeat(31));
+        assert_eq!(TileId::from_quadkey(&quadkey), Some(id.clone()));
+        assert_eq!(TileId::new(0, 0, 32).quadkey(), None);
+        assert_eq!(TileId::new(0, 0, u8::MAX).quadkey(), None);
+
+        let bounds = id.bounds();
+        assert_eq!(bounds.max().x, 180.0);
+        assert!(bounds.min().x < 180.0);
+        TileId::new(u32::MAX, u32::MAX, 32).bounds();
+    }
+
+    #[test]
+    fn bounds() {
+        let bounds = TileId::new(1, 0, 1).bounds();
+        assert_eq!((bounds.min().x, bounds.max().x), (0.0, 180.0));
+        assert!((bounds.max().y - projection::MAX_LATITUDE).abs() < 1e-9);
+        assert!(bounds.min().y.abs() < 1e-9);
+    }
+}
@@ -0,0 +1,403 @@
+use crate::{error::TileError, source::TileSource, tile_cache::TileCache, tile_id::TileId};
+use bytes::Bytes;
+use eyre::Result;
+use futures::future::{abortable, join_all, AbortHandle, Aborted, BoxFuture, FutureExt, Shared};
+use image::RgbaImage;
+use log::{debug, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+#[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+#[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TileState {
+    Loading,
+    Loaded,
+    Failed {
+        attempts: u32,
+        error: String,
+        /// `false` once the source reported that the tile does not exist.
+        retrying: bool,
+    },
+}
+
+type Download = Shared<BoxFuture<'static, Result<(), Aborted>>>;
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub(crate) struct TileLoader {
+    #[derivative(Debug = "ignore")]
+    source: Arc<dyn TileSource>,
+    cache: Mutex<TileCache>,
+    states: Mutex<HashMap<TileId, TileState>>,
+    #[derivative(Debug = "ignore")]
+    in_flight: std::sync::Mutex<HashMap<TileId, (AbortHandle, Download)>>,
+    dirty: AtomicBool,
+    #[derivative(Debug = "ignore")]
+    on_tile_loaded: Box<dyn Fn() + Send + Sync>,
+}
+
+impl TileLoader {
+    /// `on_tile_loaded` is called from the download tasks whenever a new tile is cached.
+    pub fn new<F>(source: Arc<dyn TileSource>, on_tile_loaded: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        Self {
+            source,
+            cache: Mutex::new(TileCache::default()),
+            states: Mutex::new(HashMap::new()),
+            in_flight: std::sync::Mutex::new(HashMap::new()),
+            dirty: AtomicBool::new(false),
+            on_tile_loaded: Box::new(on_tile_loaded),
+        }
+    }
+
+    pub fn source(&self) -> &dyn TileSource {
+        &*self.source
+    }
+
+    #[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+    pub fn cache(&self) -> &Mutex<TileCache> {
+        &self.cache
+    }
+
+    #[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+    pub async fn states(&self) -> HashMap<TileId, TileState> {
+        self.states.lock().await.clone()
+    }
+
+    /// Marks the tiles of the current viewport, they are kept in the cache and tracked.
+    /// Downloads of tiles that left the viewport are cancelled and the queued ones are
+    /// reordered by `ids`, which are expected to be sorted by importance.
+    pub async fn set_required(&self, ids: &[TileId]) {
+        let required: HashSet<&TileId> = ids.iter().collect();
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            in_flight.retain(|id, (handle, _)| {
+                let keep = required.contains(id);
+                if !keep {
+                    debug!("Cancelling stale tile {:?}", id);
+                    handle.abort();
+                }
+                keep
+            });
+        }
+
+        let priorities = ids
+            .iter()
+            .enumerate()
+            .map(|(priority, id)| (id.clone(), priority as u32))
+            .collect();
+        self.source.reprioritize(&priorities);
+
+        self.cache.lock().await.pin(ids.iter().cloned());
+        self.states
+            .lock()
+            .await
+            .retain(|id, _| required.contains(id));
+    }
+
+    /// Set when tiles arrived since the last call and the grid should be rebuilt.
+    #[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+    pub fn take_dirty(&self) -> bool {
+        self.dirty.swap(false, Ordering::AcqRel)
+    }
+
+    pub async fn get(&self, id: &TileId) -> Option<Arc<RgbaImage>> {
+        self.cache.lock().await.peek(id)
+    }
+
+    /// The closest cached tile on a lower zoom level that covers `id`.
+    pub async fn get_ancestor(&self, id: &TileId) -> Option<(TileId, Arc<RgbaImage>)> {
+        let cache = self.cache.lock().await;
+        let mut ancestor = id.parent();
+        while let Some(id) = ancestor {
+            if let Some(data) = cache.peek(&id) {
+                return Some((id, data));
+            }
+            ancestor = id.parent();
+        }
+        None
+    }
+
+    /// All tiles `levels` zoom levels down that cover `id`, if every one of them is cached.
+    pub async fn get_descendants(
+        &self,
+        id: &TileId,
+        levels: u32,
+    ) -> Option<Vec<(TileId, Arc<RgbaImage>)>> {
+        let mut ids = vec![id.clone()];
+        for _ in 0..levels {
+            let children = ids.iter().map(TileId::children);
+            ids = children.collect::<Option<Vec<_>>>()?.concat();
+        }
+
+        let cache = self.cache.lock().await;
+        ids.into_iter()
+            .map(|id| cache.peek(&id).map(|data| (id, data)))
+            .collect()
+    }
+
+    /// Downloads the tiles missing in the cache and returns the ones worth retrying.
+    /// `ids` are expected to be ordered by importance, the first ones are fetched first.
+    /// Tiles already being downloaded are not requested twice.
+    pub async fn load(self: &Arc<Self>, ids: &[TileId]) -> Vec<TileId> {
+        let to_download: Vec<TileId> = {
+            let mut cache = self.cache.lock().await;
+            let mut states = self.states.lock().await;
+            ids.iter()
+                .filter(|id| {
+                    let available = cache.get(id).is_some();
+                    if available {
+                        states.insert((*id).clone(), TileState::Loaded);
+                    } else if !matches!(states.get(id), Some(TileState::Failed { .. })) {
+                        states.insert((*id).clone(), TileState::Loading);
+                    }
+                    !available
+                })
+                .cloned()
+                .collect()
+        };
+        if to_download.is_empty() {
+            return Vec::new();
+        }
+
+        let downloads: Vec<Download> = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            to_download
+                .iter()
+                .enumerate()
+                .map(|(priority, id)| {
+                    let (_, download) = in_flight
+                        .entry(id.clone())
+                        .or_insert_with(|| self.start_download(id.clone(), priority as u32));
+                    download.clone()
+                })
+                .collect()
+        };
+        join_all(downloads).await;
+
+        let states = self.states.lock().await;
+        to_download
+            .into_iter()
+            .filter(|id| {
+                matches!(
+                    states.get(id),
+                    Some(TileState::Failed { retrying: true, .. })
+                )
+            })
+            .collect()
+    }
+
+    fn start_download(self: &Arc<Self>, id: TileId, priority: u32) -> (AbortHandle, Download) {
+        let loader = self.clone();
+        let (download, handle) = abortable(async move {
+            let result = match loader.source.load_tile_with_priority(&id, priority).await {
+                Ok(data) => TileLoader::decode(data).await,
+                Err(e) => Err(e),
+            };
+            loader.finish(id, result).await;
+        });
+        let download = download.boxed().shared();
+        // Spawned so that the download completes even if nobody awaits it any more
+        tokio::spawn(download.clone());
+        (handle, download)
+    }
+
+    /// Decodes PNG, JPEG or WebP on the blocking pool, keeping the render path free of
+    /// decoding work.
+    async fn decode(data: Bytes) -> Result<Arc<RgbaImage>> {
+        let now = Instant::now();
+        let image = tokio::task::spawn_blocking(move || image::load_from_memory(&data))
+            .await?
+            .map_err(|e| TileError::Decode(e.into()))?
+            .into_rgba8();
+        debug!("Decoding took {} ms", now.elapsed().as_millis());
+        Ok(Arc::new(image))
+    }
+
+    async fn finish(&self, id: TileId, result: Result<Arc<RgbaImage>>) {
+        self.in_flight.lock().unwrap().remove(&id);
+
+        let mut cache = self.cache.lock().await;
+        let mut states = self.states.lock().await;
+        match result {
+            Ok(data) => {
+                cache.insert(id.clone(), data);
+                states.insert(id, TileState::Loaded);
+                self.dirty.store(true, Ordering::Release);
+                (self.on_tile_loaded)();
+            }
+            // Failed tiles never reach the cache
+            Err(e) => {
+                warn!("Failed to load tile {:?}: {}", id, e);
+                let retrying = !matches!(
+                    e.downcast_ref::<TileError>(),
+                    Some(TileError::NotFound) | Some(TileError::Decode(_))
+                );
+                let attempts = match states.get(&id) {
+                    Some(TileState::Failed { attempts, .. }) => attempts + 1,
+                    _ => 1,
+                };
+                states.insert(
+                    id,
+                    TileState::Failed {
+                        attempts,
+                        error: e.to_string(),
+                        retrying,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Loads the tiles and keeps retrying the failed ones with a growing interval until
+    /// all of them are loaded.
+    #[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+    pub async fn run(self: Arc<Self>, ids: Vec<TileId>) {
+        let now = Instant::now();
+        let mut failed = self.load(&ids).await;
+        debug!("Tile loading took {} ms", now.elapsed().as_millis());
+
+        let mut interval = RETRY_INTERVAL;
+        while !failed.is_empty() {
+            tokio::time::sleep(interval).await;
+            debug!("Retrying {} failed tiles", failed.len());
+            failed = self.load(&failed).await;
+            interval = (interval * 2).min(MAX_RETRY_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eyre::eyre;
+
+    fn png() -> Bytes {
+        let mut data = Vec::new();
+        image::DynamicImage::ImageRgba8(RgbaImage::new(1, 1))
+            .write_to(&mut data, image::ImageOutputFormat::Png)
+            .unwrap();
+        Bytes::from(data)
+    }
+
+    struct FlakySource;
+
+    impl TileSource for FlakySource {
+        fn load_tile<'a>(&'a self, id: &'a TileId) -> BoxFuture<'a, Result<Bytes>> {
+            let result = match id.x() {
+                0 => Ok(png()),
+                3 => Ok(Bytes::from_static(b"\x89PNG\r\n\x1a\n")),
+                1 => Err(TileError::NotFound.into()),
+                _ => Err(eyre!("Connection reset")),
+            };
+            futures::future::ready(result).boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn it_works() {
+        let loader = Arc::new(TileLoader::new(Arc::new(FlakySource), || {}));
+        let ids: Vec<_> = (0..3).map(|x| TileId::new(x, 0, 2)).collect();
+        loader.set_required(&ids).await;
+
+        assert_eq!(loader.load(&ids).await, vec![ids[2].clone()]);
+        assert!(loader.get(&ids[0]).await.is_some());
+        assert!(loader.get(&ids[1]).await.is_none());
+
+        let states = loader.states().await;
+        assert_eq!(states[&ids[0]], TileState::Loaded);
+        assert!(matches!(
+            states[&ids[1]],
+            TileState::Failed {
+                attempts: 1,
+                retrying: false,
+                ..
+            }
+        ));
+
+        loader.load(&ids[2..]).await;
+        assert!(matches!(
+            loader.states().await[&ids[2]],
+            TileState::Failed {
+                attempts: 2,
+                retrying: true,
+                ..
+            }
+        ));
+
+        loader.set_required(&ids[..1]).await;
+        assert_eq!(loader.states().await.len(), 1);
+
+        let broken = TileId::new(3, 0, 2);
+        assert!(loader.load(std::slice::from_ref(&broken)).await.is_empty());
+        assert!(matches!(
+            loader.states().await[&broken],
+            TileState::Failed {
+                retrying: false,
+                ..
+            }
+        ));
+
+        let (ancestor, _) = loader.get_ancestor(&TileId::new(1, 3, 4)).await.unwrap();
+        assert_eq!(ancestor, ids[0]);
+        assert!(loader.get_ancestor(&ids[0]).await.is_none());
+
+        let parent = ids[0].parent().unwrap();
+        assert!(loader.get_descendants(&parent, 1).await.is_none());
+        let data = Arc::new(RgbaImage::new(1, 1));
+        loader
+            .cache()
+            .lock()
+            .await
+            .insert(TileId::new(1, 1, 2), data.clone());
+        assert!(loader.get_descendants(&parent, 1).await.is_none());
+        for id in &parent.children().unwrap()[1..3] {
+            loader.cache().lock().await.insert(id.clone(), data.clone());
+        }
+        assert_eq!(loader.get_descendants(&parent, 1).await.unwrap().len(), 4);
+    }
+
+    struct PendingSource;
+
+    impl TileSource for PendingSource {
+        fn load_tile<'a>(&'a self, _id: &'a TileId) -> BoxFuture<'a, Result<Bytes>> {
+            futures::future::pending().boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn stale_tiles_are_cancelled() {
+        let loader = Arc::new(TileLoader::new(Arc::new(PendingSource), || {}));
+        let ids: Vec<_> = (0..2).map(|x| TileId::new(x, 0, 2)).collect();
+        loader.set_required(&ids).await;
+
+        let load = tokio::spawn({
+            let loader = loader.clone();
+            let ids = ids.clone();
+            async move { loader.load(&ids).await }
+        });
+        while loader.in_flight.lock().unwrap().len() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        loader.set_required(&ids[..1]).await;
+        assert_eq!(loader.in_flight.lock().unwrap().len(), 1);
+        assert!(loader.in_flight.lock().unwrap().contains_key(&ids[0]));
+
+        loader.set_required(&[]).await;
+        assert!(load.await.unwrap().is_empty());
+    }
+}
@@ -1,13 +1,13 @@
 #[derive(Debug, PartialEq)]
 pub(crate) struct Rect {
-    left: f32,
-    top: f32,
-    width: f32,
-    height: f32,
+    left: f64,
+    top: f64,
+    width: f64,
+    height: f64,
 }
 
 impl Rect {
-    pub fn new(left: f32, top: f32, width: f32, height: f32) -> Self {
+    pub fn new(left: f64, top: f64, width: f64, height: f64) -> Self {
         Self {
             left,
             top,
@@ -33,43 +33,35 @@ impl Rect {
         None
     }
 
-    pub fn scale_x(mut self, scale: f32) -> Self {
+    pub fn scale_x(mut self, scale: f64) -> Self {
         self.left *= scale;
         self.width *= scale;
 
         self
     }
 
-    pub fn scale_y(mut self, scale: f32) -> Self {
+    pub fn scale_y(mut self, scale: f64) -> Self {
         self.top *= scale;
         self.height *= scale;
 
         self
     }
 
-    pub fn left(&self) -> f32 {
+    pub fn left(&self) -> f64 {
         self.left
     }
 
-    pub fn right(&self) -> f32 {
+    pub fn right(&self) -> f64 {
         self.left + self.width
     }
 
-    pub fn top(&self) -> f32 {
+    pub fn top(&self) -> f64 {
         self.top
     }
 
-    pub fn bottom(&self) -> f32 {
+    pub fn bottom(&self) -> f64 {
         self.top + self.height
     }
-
-    pub fn width(&self) -> f32 {
-        self.width
-    }
-
-    pub fn height(&self) -> f32 {
-        self.height
-    }
 }
 
 #[cfg(test)]
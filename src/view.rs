@@ -0,0 +1,221 @@
+use crate::{
+    projection, render::Camera, source::TileSource, tile::Tile, tile_coordinates::TileCoordinates,
+    tile_id::TileId, tile_loader::TileLoader, utils::Rect,
+};
+use geo::Point;
+use std::collections::HashSet;
+
+pub(crate) const TILE_SIZE: f64 = 256.0;
+/// How many zoom levels down cached tiles are looked for when a tile is missing.
+const MAX_CHILD_LEVELS: u32 = 2;
+
+pub(crate) struct TileInfo {
+    pub id: TileId,
+    pub coords: TileCoordinates,
+    /// Distance from the tile centre to the viewport centre in pixels.
+    pub distance: f64,
+}
+
+pub(crate) fn clamp_zoom(zoom: f64, source: &dyn TileSource) -> f64 {
+    zoom.max(f64::from(source.min_zoom()))
+        .min(f64::from(source.max_zoom()))
+}
+
+/// Tiles in the middle of the viewport come first, they are fetched first. Tiles
+/// repeated by the world wrapping around are listed once.
+pub(crate) fn ids_by_distance(tiles: &[TileInfo]) -> Vec<TileId> {
+    let mut by_distance: Vec<_> = tiles.iter().collect();
+    by_distance.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    let mut seen = HashSet::new();
+    by_distance
+        .into_iter()
+        .filter(|t| seen.insert(&t.id))
+        .map(|t| t.id.clone())
+        .collect()
+}
+
+/// Camera looking at `point` in world pixels of the zoom level nearest to `zoom`,
+/// the tiles of that level are scaled to the fractional part.
+pub(crate) fn camera(zoom: f64, point: &Point<f64>, width: f64, height: f64) -> Camera {
+    let level = zoom.round();
+    let center = projection::lnglat_to_world_pixel(point, level);
+    Camera::new(
+        center.x(),
+        center.y(),
+        2f64.powf(zoom - level),
+        width,
+        height,
+    )
+}
+
+/// Position of `point` on the screen of `camera`, in the world copy closest to it.
+pub(crate) fn screen_pixel(point: &Point<f64>, camera: &Camera, zoom: f64) -> Point<f64> {
+    let level = zoom.round();
+    let world_size = TILE_SIZE * 2f64.powf(level);
+    let pixel = projection::lnglat_to_world_pixel(point, level);
+    let x = pixel.x() - ((pixel.x() - camera.x) / world_size).round() * world_size;
+    camera.world_to_screen(&Point::new(x, pixel.y()))
+}
+
+/// Part of the world the camera sees, in world pixels.
+pub(crate) fn viewport(camera: &Camera) -> Rect {
+    Rect::new(
+        camera.x * camera.scale - camera.width / 2.0,
+        camera.y * camera.scale - camera.height / 2.0,
+        camera.width,
+        camera.height,
+    )
+    .scale_x(1.0 / camera.scale)
+    .scale_y(1.0 / camera.scale)
+}
+
+/// Tiles covering the viewport. Left and right of the world its tiles repeat, above
+/// and below it there are none.
+pub(crate) fn create_required_tile_infos(zoom: f64, camera: &Camera) -> Vec<TileInfo> {
+    let viewport = viewport(camera);
+    let level = zoom.round();
+    let tiles_per_side = 2f64.powf(level);
+    let mut tiles = Vec::new();
+
+    let mut tile_y = (viewport.top() / TILE_SIZE).floor().max(0.0);
+    while tile_y * TILE_SIZE < viewport.bottom() && tile_y < tiles_per_side {
+        let mut tile_x = (viewport.left() / TILE_SIZE).floor();
+        while tile_x * TILE_SIZE < viewport.right() {
+            let left = tile_x * TILE_SIZE;
+            let top = tile_y * TILE_SIZE;
+            let coords = TileCoordinates::new(left, top, TILE_SIZE);
+            let x = tile_x.rem_euclid(tiles_per_side);
+            let id = TileId::new(x as u32, tile_y as u32, level as u8);
+            let distance = (left + TILE_SIZE / 2.0 - camera.x)
+                .hypot(top + TILE_SIZE / 2.0 - camera.y)
+                * camera.scale;
+            tiles.push(TileInfo {
+                id,
+                coords,
+                distance,
+            });
+            tile_x += 1.0;
+        }
+        tile_y += 1.0;
+    }
+
+    tiles
+}
+
+/// Missing tiles are replaced by their cached children or by the part of a cached
+/// ancestor that covers them, the placeholder is only drawn when neither exists.
+pub(crate) async fn collect_tiles(
+    loader: &TileLoader,
+    required_tiles: Vec<TileInfo>,
+    viewport: &Rect,
+) -> Vec<Tile> {
+    let mut tiles = Vec::with_capacity(required_tiles.len());
+    'tiles: for t in required_tiles {
+        if let Some(data) = loader.get(&t.id).await {
+            tiles.push(Tile::new(&t.id, Some(data), &t.coords));
+            continue;
+        }
+
+        // Start of the world copy the tile is drawn in
+        let offset = t.coords.shader_coords.0 - t.id.x() as f64 * TILE_SIZE;
+        for levels in 1..=MAX_CHILD_LEVELS {
+            if let Some(children) = loader.get_descendants(&t.id, levels).await {
+                let size = TILE_SIZE / (1u32 << levels) as f64;
+                for (child, data) in children {
+                    let left = offset + child.x() as f64 * size;
+                    let top = child.y() as f64 * size;
+                    let rect = Rect::new(left, top, size, size);
+                    if rect.intersect(viewport).is_some() {
+                        let coords = TileCoordinates::new(left, top, size);
+                        tiles.push(Tile::new(&child, Some(data), &coords));
+                    }
+                }
+                continue 'tiles;
+            }
+        }
+
+        let tile = Tile::new(&t.id, None, &t.coords);
+        match loader.get_ancestor(&t.id).await {
+            Some((ancestor, data)) => tiles.push(tile.with_ancestor(&ancestor, data)),
+            None => tiles.push(tile),
+        }
+    }
+
+    tiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fractional_zoom() {
+        let point = Point::new(0.0, 0.0);
+        let camera = super::camera(0.4, &point, 100.0, 100.0);
+        assert_eq!((camera.x, camera.y), (128.0, 128.0));
+        assert!((camera.scale - 2f64.powf(0.4)).abs() < 0.001);
+        let tiles = super::create_required_tile_infos(0.4, &camera);
+        assert_eq!(tiles.len(), 1);
+        assert_eq!(tiles[0].id, TileId::new(0, 0, 0));
+        assert_eq!(tiles[0].coords.shader_coords, (0.0, 0.0, 256.0, 256.0));
+
+        let camera = super::camera(1.6, &point, 100.0, 100.0);
+        let tiles = super::create_required_tile_infos(1.6, &camera);
+        let ids: Vec<_> = tiles
+            .iter()
+            .map(|t| (t.id.x(), t.id.y(), t.id.z()))
+            .collect();
+        assert_eq!(ids, vec![(1, 1, 2), (2, 1, 2), (1, 2, 2), (2, 2, 2)]);
+
+        assert_eq!(
+            super::screen_pixel(&point, &camera, 1.6),
+            Point::new(50.0, 50.0)
+        );
+    }
+
+    #[test]
+    fn high_zoom() {
+        let point = Point::new(24.945831, 60.19206);
+        let camera = super::camera(20.0, &point, 2.0, 2.0);
+        assert_eq!(
+            super::screen_pixel(&point, &camera, 20.0),
+            Point::new(1.0, 1.0)
+        );
+        let tiles = super::create_required_tile_infos(20.0, &camera);
+        let id = projection::tile_for_lnglat(&point, 20);
+        assert!(tiles.iter().any(|t| t.id == id));
+    }
+
+    #[test]
+    fn world_wraps() {
+        // Looking at the antimeridian, the left half shows the east end of the world
+        let point = Point::new(180.0, 0.0);
+        let camera = super::camera(1.0, &point, 200.0, 100.0);
+        assert_eq!((camera.x, camera.y), (0.0, 256.0));
+        let tiles = super::create_required_tile_infos(1.0, &camera);
+        let tiles: Vec<_> = tiles
+            .iter()
+            .map(|t| (t.id.x(), t.id.y(), t.coords.shader_coords.0))
+            .collect();
+        assert_eq!(
+            tiles,
+            vec![(1, 0, -256.0), (0, 0, 0.0), (1, 1, -256.0), (0, 1, 0.0)]
+        );
+        let screen = super::screen_pixel(&Point::new(-179.0, 0.0), &camera, 1.0);
+        assert!(screen.x() > 100.0 && screen.x() < 102.0);
+        let screen = super::screen_pixel(&Point::new(179.0, 0.0), &camera, 1.0);
+        assert!(screen.x() > 98.0 && screen.x() < 100.0);
+        assert_eq!(
+            super::screen_pixel(&Point::new(181.0, 0.0), &camera, 1.0),
+            super::screen_pixel(&Point::new(-179.0, 0.0), &camera, 1.0)
+        );
+        let wrapped = super::camera(1.0, &Point::new(540.0, 0.0), 200.0, 100.0);
+        assert_eq!((wrapped.x, wrapped.y), (camera.x, camera.y));
+
+        // The world is narrower than the viewport, its only tile is loaded once
+        let camera = super::camera(0.0, &point, 600.0, 256.0);
+        let tiles = super::create_required_tile_infos(0.0, &camera);
+        assert_eq!(tiles.len(), 4);
+        assert_eq!(super::ids_by_distance(&tiles), vec![TileId::new(0, 0, 0)]);
+    }
+}